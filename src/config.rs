@@ -1,29 +1,317 @@
 use crate::{error::Error, Result};
-use kube::{api::TypeMeta, config::Kubeconfig};
+use kube::{
+    api::{DeleteParams, ListParams, TypeMeta},
+    config::Kubeconfig,
+};
 use serde::{Deserialize, Serialize};
 use std::{fs, path::Path};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub mount: Mount,
-    pub resources: Option<Vec<TypeMeta>>,
+    pub resources: Option<Vec<Resource>>,
+
+    // watches every listable kind of the cluster next to the listed resources
+    pub discovery: Option<Discovery>,
+
+    // seconds between two full relists of every watched kind, disabled if unset
+    #[serde(rename(serialize = "resync-period", deserialize = "resync-period"))]
+    pub resync_period: Option<u64>,
 
     #[serde(rename(serialize = "kube-configs", deserialize = "kube-configs"))]
     pub kube_configs: Option<Vec<KubeConfig>>,
 }
 
+// A watched kind, optionally narrowed down to the objects matching the
+// selectors in the listed namespaces.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Resource {
+    #[serde(flatten)]
+    pub types: TypeMeta,
+    pub label_selector: Option<String>,
+    pub field_selector: Option<String>,
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl Resource {
+    pub fn list_params(&self) -> ListParams {
+        ListParams {
+            label_selector: self.label_selector.clone(),
+            field_selector: self.field_selector.clone(),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Mount {
-    pub data_path: String,
+    // without a data path the tree is only kept in memory
+    pub data_path: Option<String>,
     pub path: String,
+    pub propagation_policy: Option<PropagationPolicy>,
+    pub grace_period: Option<u32>,
+    // removing a namespace directory deletes the namespace with everything in
+    // it, also the kinds that are not mounted, so it has to be enabled
+    #[serde(default)]
+    pub delete_namespaces: bool,
+    #[serde(flatten)]
+    pub view: View,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Mount {
+    pub fn delete_params(&self) -> DeleteParams {
+        DeleteParams {
+            grace_period_seconds: self.grace_period,
+            propagation_policy: self.propagation_policy.map(|p| p.into()),
+            ..Default::default()
+        }
+    }
+}
+
+// How the objects are laid out and rendered below the cluster directories.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct View {
+    #[serde(default)]
+    pub layout: Layout,
+    // every object gets a file in each format, the first one is also used for
+    // the split view and for the object files kufu looks up. Yaml if empty.
+    #[serde(default)]
+    pub formats: Vec<Format>,
+    // every object also gets a directory with its spec, status, labels and
+    // annotations as separate files
+    #[serde(default)]
+    pub split: bool,
+    // every object also gets a <name>.neat.<format> file without the fields the
+    // server manages, edits are merged into the full object
+    #[serde(default)]
+    pub neat: bool,
+    // secrets get a directory with one file per key holding the decoded value.
+    // Values are redacted everywhere unless they are revealed.
+    #[serde(default)]
+    pub reveal_secrets: bool,
+}
+
+impl From<Layout> for View {
+    fn from(layout: Layout) -> Self {
+        View {
+            layout,
+            ..Default::default()
+        }
+    }
+}
+
+impl View {
+    pub fn format(&self) -> Format {
+        self.formats.first().copied().unwrap_or_default()
+    }
+
+    // Formats the object is mounted in next to the first one.
+    pub fn alternate_formats(&self) -> &[Format] {
+        self.formats.get(1..).unwrap_or_default()
+    }
+}
+
+// How the content of object files is written, see format.rs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    Yaml,
+    Json,
+    // the object on a single line of compact json
+    JsonLines,
+}
+
+// How the directory of a kind is named below its namespace or cluster directory.
+// Namespaces always live in the namespace directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    // <ns>/deployment, of kinds sharing a name only the first one is watched
+    // and core kinds come first
+    #[default]
+    Kind,
+    // <ns>/deployments.apps, the resource names kubectl uses
+    ResourceGroup,
+    // <ns>/apps/v1/deployment, core kinds go to core/v1
+    GroupVersionKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum PropagationPolicy {
+    Orphan,
+    Background,
+    Foreground,
+}
+
+impl From<PropagationPolicy> for kube::api::PropagationPolicy {
+    fn from(value: PropagationPolicy) -> Self {
+        match value {
+            PropagationPolicy::Orphan => kube::api::PropagationPolicy::Orphan,
+            PropagationPolicy::Background => kube::api::PropagationPolicy::Background,
+            PropagationPolicy::Foreground => kube::api::PropagationPolicy::Foreground,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Discovery {
+    // api groups to watch, every group is watched if unset. The core group is
+    // named "core".
+    pub allow_groups: Option<Vec<String>>,
+    pub deny_groups: Option<Vec<String>>,
+    // seconds between two discoveries, so installed and removed crds are followed
+    pub period: Option<u64>,
+}
+
+impl Discovery {
+    pub fn allowed_groups(&self) -> Option<Vec<String>> {
+        self.allow_groups.as_ref().map(|groups| core_alias(groups))
+    }
+
+    pub fn denied_groups(&self) -> Vec<String> {
+        core_alias(self.deny_groups.as_deref().unwrap_or_default())
+    }
+}
+
+fn core_alias(groups: &[String]) -> Vec<String> {
+    groups
+        .iter()
+        .map(|g| {
+            if g == "core" {
+                String::new()
+            } else {
+                g.clone()
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct KubeConfig {
+    // name of the cluster directory, may be left out when only one cluster is mounted
+    pub name: Option<String>,
     pub config_path: Option<String>,
     pub raw: Option<Kubeconfig>,
+    // contexts mounted as their own cluster directories, "*" selects every
+    // context. Only the current context is mounted if unset.
+    pub contexts: Option<Vec<String>>,
+    // namespaces watched one by one for users who can not list kinds cluster
+    // wide, discovered on first use if unset
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl KubeConfig {
+    pub fn load(&self) -> Result<Kubeconfig> {
+        match (&self.config_path, &self.raw) {
+            (_, Some(data)) => Ok(data.to_owned()),
+            (Some(path), None) => Ok(Kubeconfig::read_from(path)?),
+            (None, None) => Err(Error::ReadKubeConfigFail(
+                "kubeconfig information is empty, please set config-path or raw kubeconfig data"
+                    .to_string(),
+            )),
+        }
+    }
+
+    // Returns the kubeconfig file to follow, for its contexts and for changes
+    // to the server and user of the mounted ones.
+    pub fn watched_path(&self) -> Option<&str> {
+        match (&self.config_path, &self.raw) {
+            (Some(path), None) => Some(path),
+            _ => None,
+        }
+    }
+
+    fn selected_contexts(&self, kubeconfig: &Kubeconfig) -> Vec<String> {
+        let selected = self.contexts.as_deref().unwrap_or_default();
+        kubeconfig
+            .contexts
+            .iter()
+            .map(|c| c.name.clone())
+            .filter(|name| selected.iter().any(|s| s == "*" || s == name))
+            .collect()
+    }
+}
+
+// A cluster directory and the kubeconfig context it is watched with.
+#[derive(Clone)]
+pub struct ClusterConfig {
+    pub name: String,
+    pub kubeconfig: Kubeconfig,
+    pub context: Option<String>,
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl ClusterConfig {
+    // Renders what the watcher of the cluster is built from, the context with
+    // its cluster and user entries and the namespaces. Other contexts of the
+    // kubeconfig are left out, so editing them keeps the cluster running.
+    pub fn fingerprint(&self) -> Result<String> {
+        let kubeconfig = &self.kubeconfig;
+        let name = self
+            .context
+            .as_ref()
+            .or(kubeconfig.current_context.as_ref());
+        let context = kubeconfig.contexts.iter().find(|c| Some(&c.name) == name);
+        let selected = context.and_then(|c| c.context.as_ref());
+        let cluster =
+            selected.and_then(|s| kubeconfig.clusters.iter().find(|c| c.name == s.cluster));
+        let user = selected.and_then(|s| kubeconfig.auth_infos.iter().find(|a| a.name == s.user));
+        Ok(serde_yaml::to_string(&(
+            context,
+            cluster,
+            user,
+            &self.namespaces,
+        ))?)
+    }
+}
+
+// Expands the kubeconfigs into the clusters to mount, every selected context
+// is mounted under its own name.
+pub fn clusters(kube_configs: &[KubeConfig], default_name: &str) -> Result<Vec<ClusterConfig>> {
+    if kube_configs.is_empty() {
+        return Err(Error::ReadKubeConfigFail(
+            "kube-configs is empty".to_string(),
+        ));
+    }
+    let mut clusters: Vec<ClusterConfig> = Vec::with_capacity(kube_configs.len());
+    for c in kube_configs {
+        let kubeconfig = c.load()?;
+        let named = match (&c.contexts, &c.name, kube_configs.len()) {
+            (Some(_), _, _) => c
+                .selected_contexts(&kubeconfig)
+                .into_iter()
+                // context names like eks arns may contain slashes
+                .map(|context| (context.replace('/', "_"), Some(context)))
+                .collect(),
+            (None, Some(name), _) => vec![(name.clone(), None)],
+            (None, None, 1) => vec![(default_name.to_string(), None)],
+            (None, None, _) => return Err(Error::ReadKubeConfigFail(
+                "every kube-configs entry needs a name or contexts when mounting several clusters"
+                    .to_string(),
+            )),
+        };
+        for (name, context) in named {
+            if clusters.iter().any(|c| c.name == name) {
+                return Err(Error::ReadKubeConfigFail(format!(
+                    "cluster name {} is used more than once",
+                    name
+                )));
+            }
+            clusters.push(ClusterConfig {
+                name,
+                kubeconfig: kubeconfig.clone(),
+                context,
+                namespaces: c.namespaces.clone(),
+            });
+        }
+    }
+    Ok(clusters)
 }
 
 pub fn load<P>(config_path: P) -> Result<Config>
@@ -33,16 +321,16 @@ where
     let kufu_config = match fs::read(config_path) {
         Ok(data) => data,
         Err(err) => {
-            println!("read config fail: {}", err.to_string());
+            println!("read config fail: {}", err);
             return Err(Error::ReadKubeConfigFail(err.to_string()));
         }
     };
     let config: Config = match serde_yaml::from_slice(&kufu_config) {
         Ok(config) => config,
         Err(err) => {
-            println!("decode config yaml fail: {}", err.to_string());
+            println!("decode config yaml fail: {}", err);
             return Err(Error::ReadKubeConfigFail(err.to_string()));
         }
     };
-    return Ok(config);
+    Ok(config)
 }
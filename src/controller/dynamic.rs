@@ -1 +1,84 @@
+use super::ensure_namespace;
+use crate::{
+    db::Storage, ClusterObject, ClusterObjectMeta, EventHandler, EventHandlerFactory, FactoryClone,
+    ObjectEvent, Result,
+};
+use async_trait::async_trait;
+use kube::{core::DynamicObject, discovery::Scope, Client};
+use std::sync::Arc;
+use tracing::info;
 
+// Handles any kind without a dedicated controller, objects are mounted under
+// their namespace or the cluster directory depending on the resource scope.
+struct DynamicController {
+    store: Arc<Box<dyn Storage>>,
+    meta: ClusterObjectMeta,
+}
+
+impl DynamicController {
+    fn new(meta: ClusterObjectMeta, store: Arc<Box<dyn Storage>>) -> DynamicController {
+        DynamicController { meta, store }
+    }
+    fn to_cluster_obj<'a>(&'a self, o: &'a DynamicObject) -> ClusterObject<'a> {
+        ClusterObject {
+            meta: &self.meta,
+            obj: o,
+        }
+    }
+    fn on_apply(&self, o: DynamicObject) -> Result<()> {
+        let name = &o.metadata.name.clone().unwrap();
+        match self.meta.caps.scope {
+            Scope::Namespaced => {
+                let ns = &o.metadata.namespace.clone().unwrap();
+                info!(
+                    "watched {}: {}/{} apply event",
+                    self.meta.gvk.kind, ns, name
+                );
+                ensure_namespace(&**self.store, &self.meta, ns)?;
+            }
+            Scope::Cluster => info!("watched {}: {} apply event", self.meta.gvk.kind, name),
+        }
+        self.store.add(self.to_cluster_obj(&o))
+    }
+    fn on_delete(&self, o: DynamicObject) -> Result<()> {
+        let name = &o.metadata.name.clone().unwrap();
+        info!("watched {}: {} delete event", self.meta.gvk.kind, name);
+        self.store.delete(self.to_cluster_obj(&o))
+    }
+}
+
+#[async_trait]
+impl EventHandler for DynamicController {
+    async fn process(&self, e: ObjectEvent) -> Result<()> {
+        match e {
+            ObjectEvent::Applied(o) => self.on_apply(o),
+            ObjectEvent::Deleted(o) => self.on_delete(o),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DynamicControllerFactory;
+
+impl DynamicControllerFactory {
+    pub fn new_box() -> Box<DynamicControllerFactory> {
+        Box::new(DynamicControllerFactory)
+    }
+}
+
+impl EventHandlerFactory for DynamicControllerFactory {
+    fn build(
+        &self,
+        meta: ClusterObjectMeta,
+        _client: Client,
+        store: Arc<Box<dyn Storage>>,
+    ) -> Box<dyn EventHandler> {
+        Box::new(DynamicController::new(meta, store))
+    }
+}
+
+impl FactoryClone for DynamicControllerFactory {
+    fn clone_box(&self) -> Box<dyn EventHandlerFactory> {
+        Box::new(*self)
+    }
+}
@@ -1,11 +1,72 @@
 pub mod dynamic;
 pub mod ns;
 pub mod pod;
+pub mod queue;
+pub use dynamic::*;
 pub use ns::*;
 pub use pod::*;
 
-use crate::Result;
+use crate::{db::Storage, ClusterObject, ClusterObjectMeta, Result};
+use kube::{
+    core::{DynamicObject, GroupVersionKind},
+    discovery::{ApiCapabilities, ApiResource, Scope},
+};
+use std::collections::HashSet;
+use tracing::info;
 
-trait Controller {
-    fn resync(&self) -> Result<()>;
+// Drops the local objects of the kind that are missing from objs, only within
+// the namespace if the list came from a watch of a single namespace.
+// Placeholder namespaces carry no version and stay until they are watched.
+pub(crate) fn prune(
+    store: &dyn Storage,
+    meta: &ClusterObjectMeta,
+    namespace: Option<&str>,
+    objs: &[DynamicObject],
+) -> Result<()> {
+    let alive: HashSet<(Option<&String>, Option<&String>)> = objs
+        .iter()
+        .map(|o| (o.metadata.namespace.as_ref(), o.metadata.name.as_ref()))
+        .collect();
+    for o in store.list(meta)? {
+        if namespace.is_some() && o.metadata.namespace.as_deref() != namespace {
+            continue;
+        }
+        if o.metadata.resource_version.is_none() {
+            continue;
+        }
+        if alive.contains(&(o.metadata.namespace.as_ref(), o.metadata.name.as_ref())) {
+            continue;
+        }
+        info!(
+            "prune {}: {}/{} which is gone from cluster",
+            meta.gvk.kind,
+            o.metadata.namespace.clone().unwrap_or_default(),
+            o.metadata.name.clone().unwrap()
+        );
+        store.delete(ClusterObject { meta, obj: &o })?;
+    }
+    Ok(())
+}
+
+// Mounts a placeholder namespace object, so namespaced resources have a parent
+// directory before the namespace itself is watched.
+fn ensure_namespace(store: &dyn Storage, meta: &ClusterObjectMeta, ns: &str) -> Result<()> {
+    let ns_ar = ApiResource::from_gvk(&GroupVersionKind::gvk("", "v1", "Namespace"));
+    let ns_obj = ClusterObject {
+        meta: &ClusterObjectMeta::new(
+            meta.cluster.clone(),
+            &ns_ar,
+            ApiCapabilities {
+                subresources: vec![],
+                operations: vec![],
+                scope: Scope::Cluster,
+            },
+            meta.view.clone(),
+        ),
+        obj: &DynamicObject::new(ns, &ns_ar),
+    };
+    if !store.has(&ns_obj)? {
+        store.add(ns_obj)?
+    }
+    Ok(())
 }
@@ -1,16 +1,10 @@
-use super::Controller;
 use crate::{
     db::Storage, ClusterObject, ClusterObjectMeta, EventHandler, EventHandlerFactory, FactoryClone,
-    Result,
+    ObjectEvent, Result,
 };
 use async_trait::async_trait;
 
-use kube::{
-    core::DynamicObject,
-    runtime::watcher::Event,
-    runtime::watcher::Event::{Applied, Deleted, Restarted},
-    Client,
-};
+use kube::{core::DynamicObject, Client};
 use std::sync::Arc;
 use tracing::info;
 
@@ -24,7 +18,7 @@ impl NamespaceController {
     fn new(meta: ClusterObjectMeta, store: Arc<Box<dyn Storage>>) -> NamespaceController {
         NamespaceController { meta, store }
     }
-    fn to_cluster_obj<'a>(&'a self, o: &'a DynamicObject) -> ClusterObject {
+    fn to_cluster_obj<'a>(&'a self, o: &'a DynamicObject) -> ClusterObject<'a> {
         ClusterObject {
             meta: &self.meta,
             obj: o,
@@ -40,27 +34,14 @@ impl NamespaceController {
         info!("watched namespace: {} delete event", ns);
         self.store.delete(self.to_cluster_obj(&o))
     }
-    fn on_resync(&self, objs: Vec<DynamicObject>) -> Result<()> {
-        for o in objs {
-            self.on_apply(o)?;
-        }
-        Ok(())
-    }
-}
-
-impl Controller for NamespaceController {
-    fn resync(&self) -> Result<()> {
-        todo!()
-    }
 }
 
 #[async_trait]
 impl EventHandler for NamespaceController {
-    async fn process(&self, e: Event<DynamicObject>) -> Result<()> {
+    async fn process(&self, e: ObjectEvent) -> Result<()> {
         match e {
-            Applied(o) => self.on_apply(o),
-            Deleted(o) => self.on_delete(o),
-            Restarted(o) => self.on_resync(o),
+            ObjectEvent::Applied(o) => self.on_apply(o),
+            ObjectEvent::Deleted(o) => self.on_delete(o),
         }
     }
 }
@@ -87,6 +68,6 @@ impl EventHandlerFactory for NamespaceControllerFactory {
 
 impl FactoryClone for NamespaceControllerFactory {
     fn clone_box(&self) -> Box<dyn EventHandlerFactory> {
-        Box::new(self.clone())
+        Box::new(*self)
     }
 }
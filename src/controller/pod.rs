@@ -1,18 +1,11 @@
-use super::Controller;
+use super::ensure_namespace;
 use crate::{
     db::Storage, ClusterObject, ClusterObjectMeta, EventHandler, EventHandlerFactory, FactoryClone,
-    Result,
+    ObjectEvent, Result,
 };
 use async_trait::async_trait;
 use k8s_openapi::api::core::v1::Namespace;
-use kube::{
-    api::Api,
-    core::{DynamicObject, GroupVersionKind},
-    discovery::{ApiCapabilities, ApiResource, Scope},
-    runtime::watcher::Event,
-    runtime::watcher::Event::{Applied, Deleted, Restarted},
-    Client,
-};
+use kube::{api::Api, core::DynamicObject, Client};
 use std::sync::Arc;
 use tracing::info;
 
@@ -32,7 +25,7 @@ impl PodController {
             store,
         }
     }
-    fn to_cluster_obj<'a>(&'a self, o: &'a DynamicObject) -> ClusterObject {
+    fn to_cluster_obj<'a>(&'a self, o: &'a DynamicObject) -> ClusterObject<'a> {
         ClusterObject {
             meta: &self.meta,
             obj: o,
@@ -42,22 +35,7 @@ impl PodController {
         let ns = &o.metadata.namespace.clone().unwrap();
         let name = &o.metadata.name.clone().unwrap();
         info!("watched pod: {}/{} apply event", ns, name);
-        let ns_gvk = GroupVersionKind::gvk("".into(), "v1".into(), "Namespace".into());
-        let ns_obj = ClusterObject {
-            meta: &ClusterObjectMeta {
-                cluster: self.meta.cluster.clone(),
-                gvk: ns_gvk.clone(),
-                caps: ApiCapabilities {
-                    subresources: vec![],
-                    operations: vec![],
-                    scope: Scope::Cluster,
-                },
-            },
-            obj: &DynamicObject::new(ns.as_str().clone(), &ApiResource::from_gvk(&ns_gvk)),
-        };
-        if !self.store.has(&ns_obj)? {
-            self.store.add(ns_obj)?
-        }
+        ensure_namespace(&**self.store, &self.meta, ns)?;
         self.store.add(self.to_cluster_obj(&o))
     }
     fn on_delete(&self, o: DynamicObject) -> Result<()> {
@@ -66,27 +44,14 @@ impl PodController {
         info!("watched pod: {}/{} delete event", ns, name);
         self.store.delete(self.to_cluster_obj(&o))
     }
-    fn on_resync(&self, objs: Vec<DynamicObject>) -> Result<()> {
-        for o in objs {
-            self.on_apply(o)?;
-        }
-        Ok(())
-    }
-}
-
-impl Controller for PodController {
-    fn resync(&self) -> Result<()> {
-        todo!()
-    }
 }
 
 #[async_trait]
 impl EventHandler for PodController {
-    async fn process(&self, e: Event<DynamicObject>) -> Result<()> {
+    async fn process(&self, e: ObjectEvent) -> Result<()> {
         match e {
-            Applied(o) => self.on_apply(o),
-            Deleted(o) => self.on_delete(o),
-            Restarted(o) => self.on_resync(o),
+            ObjectEvent::Applied(o) => self.on_apply(o),
+            ObjectEvent::Deleted(o) => self.on_delete(o),
         }
     }
 }
@@ -113,6 +78,6 @@ impl EventHandlerFactory for PodControllerFactory {
 
 impl FactoryClone for PodControllerFactory {
     fn clone_box(&self) -> Box<dyn EventHandlerFactory> {
-        Box::new(self.clone())
+        Box::new(*self)
     }
 }
@@ -1 +1,212 @@
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
+const BASE_DELAY: Duration = Duration::from_millis(5);
+const MAX_DELAY: Duration = Duration::from_secs(1000);
+const QPS: f64 = 10.0;
+const BURST: f64 = 100.0;
+
+// A work queue in the style of controller-runtime: a key is queued at most once,
+// the latest value added for it wins, and a key being processed is only handed
+// out again after done is called. Every added value gets a generation, so a
+// retry of a value that was replaced in the meantime is dropped.
+pub struct WorkQueue<K, V> {
+    state: Mutex<QueueState<K, V>>,
+    notify: Notify,
+    limiter: RateLimiter<K>,
+}
+
+struct QueueState<K, V> {
+    queue: VecDeque<K>,
+    dirty: HashSet<K>,
+    processing: HashSet<K>,
+    items: HashMap<K, (u64, V)>,
+    generation: u64,
+    // generation of the latest value added for the key
+    latest: HashMap<K, u64>,
+    // generation of the value handed out for the key
+    handed: HashMap<K, u64>,
+    shutting_down: bool,
+}
+
+impl<K, V> WorkQueue<K, V>
+where
+    K: Clone + Eq + Hash + Send + 'static,
+    V: Send + 'static,
+{
+    pub fn new() -> Arc<WorkQueue<K, V>> {
+        Arc::new(WorkQueue {
+            state: Mutex::new(QueueState {
+                queue: VecDeque::new(),
+                dirty: HashSet::new(),
+                processing: HashSet::new(),
+                items: HashMap::new(),
+                generation: 0,
+                latest: HashMap::new(),
+                handed: HashMap::new(),
+                shutting_down: false,
+            }),
+            notify: Notify::new(),
+            limiter: RateLimiter::new(),
+        })
+    }
+
+    pub fn add(&self, key: K, value: V) {
+        let mut state = self.state.lock().unwrap();
+        if state.shutting_down {
+            return;
+        }
+        state.generation += 1;
+        let generation = state.generation;
+        state.latest.insert(key.clone(), generation);
+        state.items.insert(key.clone(), (generation, value));
+        if !state.dirty.insert(key.clone()) || state.processing.contains(&key) {
+            return;
+        }
+        state.queue.push_back(key);
+        self.notify.notify_one();
+    }
+
+    // Queues the value handed out for the key again once the rate limiter
+    // allows it, unless a newer value for the key was added since it was
+    // handed out. Must be called before done.
+    pub fn add_rate_limited(self: &Arc<Self>, key: K, value: V) {
+        let delay = self.limiter.when(&key);
+        let generation = self.state.lock().unwrap().handed.get(&key).copied();
+        let queue = Arc::clone(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let latest = queue.state.lock().unwrap().latest.get(&key).copied();
+            if generation.is_none() || latest != generation {
+                return;
+            }
+            queue.add(key, value);
+        });
+    }
+
+    pub async fn get(&self) -> Option<(K, V)> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(key) = state.queue.pop_front() {
+                    state.dirty.remove(&key);
+                    state.processing.insert(key.clone());
+                    let (generation, value) = state.items.remove(&key).unwrap();
+                    state.handed.insert(key.clone(), generation);
+                    return Some((key, value));
+                }
+                if state.shutting_down {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn done(&self, key: &K) {
+        let mut state = self.state.lock().unwrap();
+        state.processing.remove(key);
+        state.handed.remove(key);
+        if state.dirty.contains(key) {
+            state.queue.push_back(key.clone());
+            self.notify.notify_one();
+        }
+    }
+
+    // Clears the failure history of the key after it was processed successfully,
+    // pending retries of older values are dropped.
+    pub fn forget(&self, key: &K) {
+        self.limiter.forget(key);
+        let mut state = self.state.lock().unwrap();
+        if !state.items.contains_key(key) {
+            state.latest.remove(key);
+        }
+    }
+
+    pub fn retries(&self, key: &K) -> u32 {
+        self.limiter.retries(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn shut_down(&self) {
+        self.state.lock().unwrap().shutting_down = true;
+        self.notify.notify_waiters();
+    }
+}
+
+// Combines a per key exponential backoff with an overall token bucket, the
+// longer of the two delays is used.
+struct RateLimiter<K> {
+    failures: Mutex<HashMap<K, u32>>,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    fn new() -> RateLimiter<K> {
+        RateLimiter {
+            failures: Mutex::new(HashMap::new()),
+            bucket: Mutex::new(TokenBucket::new(QPS, BURST)),
+        }
+    }
+
+    fn when(&self, key: &K) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let retries = failures.entry(key.clone()).or_insert(0);
+        let backoff = BASE_DELAY
+            .checked_mul(2u32.saturating_pow(*retries))
+            .map_or(MAX_DELAY, |d| min(d, MAX_DELAY));
+        *retries += 1;
+        max(backoff, self.bucket.lock().unwrap().reserve())
+    }
+
+    fn forget(&self, key: &K) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    fn retries(&self, key: &K) -> u32 {
+        *self.failures.lock().unwrap().get(key).unwrap_or(&0)
+    }
+}
+
+struct TokenBucket {
+    qps: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(qps: f64, burst: f64) -> TokenBucket {
+        TokenBucket {
+            qps,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // Takes a token and returns how long the caller has to wait for it.
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.qps).min(self.burst);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.qps)
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use super::Bucket::*;
+use super::{Backend, Batch, Bucket, Store};
+use crate::Result;
+use sled::transaction::ConflictableTransactionError;
+use sled::{IVec, Transactional, Tree};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::{collections::HashMap, path::Path};
+
+// Bumped whenever the encoding of the stored tree changes, a tree written in
+// another format is dropped on open.
+const FORMAT_KEY: &str = "format";
+const FORMAT_VERSION: &[u8] = b"2";
+
+pub type SledDb = Store<SledBackend>;
+
+impl AsRef<[u8]> for Bucket {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            RIndex => "reverse-index".as_bytes(),
+            Inode => "inode".as_bytes(),
+            Dentry => "dentry".as_bytes(),
+            Entry => "dentry-entry".as_bytes(),
+            Data => "data".as_bytes(),
+            Object => "object-index".as_bytes(),
+            Part => "view-part".as_bytes(),
+            WatchState => "watch-state".as_bytes(),
+        }
+    }
+}
+
+// Keeps every bucket in a sled tree under the data path.
+#[derive(Clone)]
+pub struct SledBackend {
+    buckets: HashMap<Bucket, Tree>,
+}
+
+impl SledDb {
+    // The tree mounted by the last run is kept, so watches can resume from the
+    // resource versions they stopped at.
+    pub fn new(path: impl AsRef<Path>) -> Result<SledDb> {
+        let db = sled::open(path)?;
+        let mut buckets = HashMap::new();
+        for bucket in [RIndex, Inode, Dentry, Entry, Data, Object, Part, WatchState] {
+            buckets.insert(bucket, db.open_tree(bucket)?);
+        }
+        let backend = SledBackend { buckets };
+        if db.get(FORMAT_KEY)?.as_deref() != Some(FORMAT_VERSION) {
+            backend.clear()?;
+            db.insert(FORMAT_KEY, FORMAT_VERSION)?;
+        }
+        Ok(Store::with_backend(backend))
+    }
+}
+
+impl SledBackend {
+    fn tree(&self, bucket: Bucket) -> &Tree {
+        self.buckets.get(&bucket).unwrap()
+    }
+}
+
+impl Backend for SledBackend {
+    fn get(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<Option<IVec>> {
+        Ok(self.tree(bucket).get(key)?)
+    }
+
+    fn insert(&self, bucket: Bucket, key: impl AsRef<[u8]>, value: impl Into<IVec>) -> Result<()> {
+        self.tree(bucket).insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<()> {
+        self.tree(bucket).remove(key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        self.tree(bucket).scan_prefix(prefix).map(|item| Ok(item?))
+    }
+
+    fn scan_prefix_after(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+        after: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        let prefix = IVec::from(prefix.as_ref());
+        let after = IVec::from(after.as_ref());
+        self.tree(bucket)
+            .range((Excluded(after), Unbounded))
+            .take_while(move |item| {
+                item.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            })
+            .map(|item| Ok(item?))
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        let mut buckets: Vec<Bucket> = vec![];
+        for (bucket, _, _) in batch.ops() {
+            if !buckets.contains(bucket) {
+                buckets.push(*bucket);
+            }
+        }
+        let trees: Vec<&Tree> = buckets.iter().map(|b| self.tree(*b)).collect();
+        trees[..].transaction(|views| {
+            for (bucket, key, value) in batch.ops() {
+                let view = &views[buckets.iter().position(|b| b == bucket).unwrap()];
+                match value {
+                    Some(value) => view.insert(key, value)?,
+                    None => view.remove(key)?,
+                };
+            }
+            Ok::<(), ConflictableTransactionError>(())
+        })?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        for tree in self.buckets.values() {
+            tree.clear()?;
+        }
+        Ok(())
+    }
+}
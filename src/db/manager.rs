@@ -1,156 +1,240 @@
 use super::Bucket::*;
-use super::{FSManger, SledDb, Storage};
+use super::{Backend, Batch, FSManger, Store};
 use crate::db::utils::*;
-use crate::error::Error::{ClusterObjectDataNotFound, DentryAttrNotFound, InodeAttrNotFound};
-use crate::fuse::core::{time_now, DentryAttributes, FileKind, InodeAttributes};
+use crate::error::Error::{
+    ClusterObjectDataNotFound, DentryAttrNotFound, InodeAttrNotFound, PathNotFound,
+};
+use crate::fuse::core::{
+    decode_entry, encode_entry, time_now, DentryAttributes, FileKind, InodeAttributes,
+};
 use crate::Result;
 use sled::IVec;
-use sled::Transactional;
 use std::path::Path;
 
-impl FSManger for SledDb {
+impl<B: Backend> FSManger for Store<B> {
     fn mount_dir(&self, path: impl AsRef<Path>, parent_inode: u64) -> Result<u64> {
         let name = extract_name(path.as_ref());
         let key = into_string(path.as_ref());
 
-        if self.get_bucket(RIndex).contains_key(key.clone())? {
-            let inode = self.get_bucket(RIndex).get(key.clone())?.unwrap();
+        if self.backend().contains_key(RIndex, key.clone())? {
+            let inode = self.backend().get(RIndex, key.clone())?.unwrap();
             return Ok(ivec_to_u64(&inode));
         }
-        let next_inode = handle_next_inode();
-        let inode_attr: IVec = InodeAttributes::new_dict(next_inode.0).into();
-        let entries = if parent_inode != 0 {
-            vec![
-                (".".to_string(), (FileKind::Directory, next_inode.0)),
-                ("..".to_string(), (FileKind::Directory, parent_inode)),
-            ]
-        } else {
-            vec![(".".to_string(), (FileKind::Directory, next_inode.0))]
+        let next_inode = match parent_inode {
+            0 => (ROOT_INODE, u64_to_ivec(ROOT_INODE)),
+            _ => self.next_inode(&key)?,
         };
+        let inode_attr: IVec = InodeAttributes::new_dict(next_inode.0, parent_inode).into();
         let dentry_attr: IVec = DentryAttributes {
             parent: parent_inode,
             name: name.clone(),
-            entries: entries.into_iter().collect(),
         }
         .into();
         self.join_dir(parent_inode, next_inode.0, name, FileKind::Directory)?;
-        (
-            self.get_bucket(RIndex),
-            self.get_bucket(Inode),
-            self.get_bucket(Dentry),
-        )
-            .transaction(|(rindx, inode, dentry)| {
-                rindx.insert(key.as_bytes(), next_inode.1.clone())?;
-                inode.insert(next_inode.1.clone(), inode_attr.clone())?;
-                dentry.insert(next_inode.1.clone(), dentry_attr.clone())?;
-                Ok(())
-            })?;
+        let mut batch = Batch::default();
+        batch.insert(RIndex, key.as_bytes(), next_inode.1.clone());
+        batch.insert(Inode, &next_inode.1, inode_attr);
+        batch.insert(Dentry, &next_inode.1, dentry_attr);
+        self.backend().apply(batch)?;
         Ok(next_inode.0)
     }
 
     fn mount_file(&self, path: impl AsRef<Path>, parent_inode: u64, content: IVec) -> Result<u64> {
         let name = extract_name(path.as_ref());
         let key = into_string(path.as_ref());
-        let next_inode = if self.get_bucket(RIndex).contains_key(key.clone())? {
-            let inode = self.get_bucket(RIndex).get(key.clone())?.unwrap();
+        let next_inode = if self.backend().contains_key(RIndex, key.clone())? {
+            let inode = self.backend().get(RIndex, key.clone())?.unwrap();
             (ivec_to_u64(&inode), inode)
         } else {
-            handle_next_inode()
+            self.next_inode(&key)?
         };
-        let inode_attr: IVec = InodeAttributes::new_file(next_inode.0, content.len() as u64).into();
+        let inode_attr: IVec =
+            InodeAttributes::new_file(next_inode.0, parent_inode, content.len() as u64).into();
         self.join_dir(parent_inode, next_inode.0, name, FileKind::File)?;
-        (
-            self.get_bucket(RIndex),
-            self.get_bucket(Inode),
-            self.get_bucket(Data),
-        )
-            .transaction(|(rindx, inode, data)| {
-                rindx.insert(key.as_bytes(), next_inode.1.clone())?;
-                inode.insert(next_inode.1.clone(), inode_attr.clone())?;
-                data.insert(next_inode.1.clone(), content.clone())?;
-                Ok(())
-            })?;
+        let mut batch = Batch::default();
+        batch.insert(RIndex, key.as_bytes(), next_inode.1.clone());
+        batch.insert(Inode, &next_inode.1, inode_attr);
+        batch.insert(Data, &next_inode.1, content);
+        self.backend().apply(batch)?;
         Ok(next_inode.0)
     }
 
     fn edit_file(&self, path: impl AsRef<Path>, content: IVec) -> Result<()> {
+        let inode = self.get_inode(into_string(path.as_ref()))?;
+        self.set_data(inode, content)
+    }
+
+    fn unmount(&self, path: impl AsRef<Path>) -> Result<()> {
         let key = into_string(path.as_ref());
-        let inode_ivec = self.get_bucket(Inode).get(key)?.unwrap();
-        fn update_inode_attr(old: Option<&[u8]>) -> Option<InodeAttributes> {
-            match old {
-                Some(bytes) => {
-                    let mut inode_attr: InodeAttributes = bytes.try_into().unwrap();
-                    inode_attr.last_modified = time_now();
-                    inode_attr.last_metadata_changed = time_now();
-                    Some(inode_attr)
+        let inode = match self.backend().get(RIndex, key.clone())? {
+            Some(inode) => inode,
+            None => return Ok(()),
+        };
+        match self.get_dentry(ivec_to_u64(&inode)) {
+            Ok(dentry) => {
+                let names = self
+                    .entries(ivec_to_u64(&inode))
+                    .map(|entry| entry.map(|(name, _)| name))
+                    .collect::<Result<Vec<_>>>()?;
+                for name in names {
+                    self.unmount(path.as_ref().join(name))?;
+                }
+                self.leave_dir(dentry.parent, dentry.name)?;
+            }
+            Err(_) => {
+                let parent_key = into_string(path.as_ref().parent().unwrap());
+                if let Some(parent_inode) = self.backend().get(RIndex, parent_key)? {
+                    self.leave_dir(ivec_to_u64(&parent_inode), extract_name(path.as_ref()))?;
                 }
-                None => None,
             }
         }
-        self.get_bucket(Inode)
-            .fetch_and_update(inode_ivec.clone(), update_inode_attr)?;
-        self.get_bucket(Data)
-            .insert(inode_ivec.clone(), content.clone())?;
+        let mut batch = Batch::default();
+        batch.remove(RIndex, key.as_bytes());
+        batch.remove(Inode, &inode);
+        batch.remove(Dentry, &inode);
+        batch.remove(Data, &inode);
+        batch.remove(Part, &inode);
+        self.backend().apply(batch)?;
         Ok(())
     }
 
+    // Inodes are derived from the path, so a path keeps its inode across
+    // restarts and remounts. A taken inode is skipped, the root is always 1.
+    fn next_inode(&self, key: &str) -> Result<SledInode> {
+        let mut inode = path_inode(key);
+        while inode <= ROOT_INODE || self.backend().contains_key(Inode, u64_to_ivec(inode))? {
+            inode = inode.wrapping_add(1) & INODE_MASK;
+        }
+        Ok((inode, u64_to_ivec(inode)))
+    }
+
     fn join_dir(&self, parent_inode: u64, inode: u64, name: String, kind: FileKind) -> Result<()> {
         if parent_inode == 0 {
             return Ok(());
         }
-        let update_dentry_attr = |old: Option<&[u8]>| -> Option<DentryAttributes> {
-            match old {
-                Some(bytes) => {
-                    let mut inode_attr: DentryAttributes = bytes.try_into().unwrap();
-                    inode_attr.entries.insert(name.clone(), (kind, inode));
-                    Some(inode_attr)
-                }
-                None => None,
-            }
-        };
-        self.get_bucket(Dentry)
-            .fetch_and_update(u64_to_ivec(parent_inode), update_dentry_attr)?;
+        self.backend().insert(
+            Entry,
+            entry_key(parent_inode, &name),
+            encode_entry(kind, inode),
+        )?;
+        Ok(())
+    }
+
+    fn leave_dir(&self, parent_inode: u64, name: String) -> Result<()> {
+        if parent_inode == 0 {
+            return Ok(());
+        }
+        self.backend()
+            .remove(Entry, entry_key(parent_inode, &name))?;
         Ok(())
     }
 
     fn get_dentry(&self, inode: u64) -> Result<DentryAttributes> {
-        let dentry_bucket = self.get_bucket(Dentry);
-        let inode_key = u64_to_ivec(inode);
-        if !dentry_bucket.contains_key(inode_key.clone())? {
-            return Err(DentryAttrNotFound(inode));
+        match self.backend().get(Dentry, u64_to_ivec(inode))? {
+            Some(attr) => attr.try_into(),
+            None => Err(DentryAttrNotFound(inode)),
+        }
+    }
+
+    fn get_entry(&self, parent_inode: u64, name: &str) -> Result<Option<(FileKind, u64)>> {
+        match self.backend().get(Entry, entry_key(parent_inode, name))? {
+            Some(entry) => Ok(Some(decode_entry(&entry)?)),
+            None => Ok(None),
         }
-        let attr: DentryAttributes = dentry_bucket.get(inode_key)?.unwrap().try_into()?;
-        Ok(attr)
+    }
+
+    // Entries come sorted by name, "." and ".." are not stored.
+    fn entries(&self, inode: u64) -> impl Iterator<Item = Result<(String, (FileKind, u64))>> {
+        self.backend()
+            .scan_prefix(Entry, inode.to_be_bytes())
+            .map(named_entry)
+    }
+
+    // The entries sorted after name, a listing continues there without
+    // scanning the entries it returned already.
+    fn entries_after(
+        &self,
+        inode: u64,
+        name: &str,
+    ) -> impl Iterator<Item = Result<(String, (FileKind, u64))>> {
+        self.backend()
+            .scan_prefix_after(Entry, inode.to_be_bytes(), entry_key(inode, name))
+            .map(named_entry)
+    }
+
+    fn is_empty_dir(&self, inode: u64) -> Result<bool> {
+        Ok(self
+            .backend()
+            .scan_prefix(Entry, inode.to_be_bytes())
+            .next()
+            .is_none())
     }
 
     fn get_inode_attr(&self, inode: u64) -> Result<InodeAttributes> {
-        let inode_bucket = self.get_bucket(Inode);
-        let inode_key = u64_to_ivec(inode);
-        if !inode_bucket.contains_key(inode_key.clone())? {
-            return Err(InodeAttrNotFound(inode));
+        match self.backend().get(Inode, u64_to_ivec(inode))? {
+            Some(attr) => attr.try_into(),
+            None => Err(InodeAttrNotFound(inode)),
         }
-        let attr: InodeAttributes = inode_bucket.get(inode_key)?.unwrap().try_into()?;
-        Ok(attr)
     }
 
     fn update_inode(&self, inode: u64, attr: InodeAttributes) -> Result<()> {
-        let value: IVec = attr.try_into()?;
-        self.get_bucket(Inode).insert(u64_to_ivec(inode), value)?;
+        let value: IVec = attr.into();
+        self.backend().insert(Inode, u64_to_ivec(inode), value)?;
         Ok(())
     }
 
     fn get_inode(&self, key: String) -> Result<u64> {
-        let inode = self.get_bucket(RIndex).get(key)?.unwrap();
-        Ok(ivec_to_u64(&inode))
+        match self.backend().get(RIndex, &key)? {
+            Some(inode) => Ok(ivec_to_u64(&inode)),
+            None => Err(PathNotFound(key)),
+        }
     }
 
     fn get_data(&self, inode: u64) -> Result<IVec> {
-        let data_bucket = self.get_bucket(Data);
-        let obj_key = u64_to_ivec(inode);
-        if !data_bucket.contains_key(&obj_key)? {
-            return Err(ClusterObjectDataNotFound(inode));
+        match self.backend().get(Data, u64_to_ivec(inode))? {
+            Some(data) => Ok(data),
+            None => Err(ClusterObjectDataNotFound(inode)),
         }
-        let data = data_bucket.get(&obj_key)?.unwrap();
-        return Ok(data);
     }
+
+    fn get_path(&self, inode: u64) -> Result<String> {
+        let mut names = vec![];
+        let mut dentry = self.get_dentry(inode)?;
+        while dentry.parent != 0 {
+            names.push(dentry.name.clone());
+            dentry = self.get_dentry(dentry.parent)?;
+        }
+        names.reverse();
+        Ok(names.join("/"))
+    }
+
+    // Returns the file of the object and the part, if the inode is a part of a
+    // split view.
+    fn get_part(&self, inode: u64) -> Result<Option<(u64, String)>> {
+        match self.backend().get(Part, u64_to_ivec(inode))? {
+            Some(value) => Ok(Some(decode_part(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_data(&self, inode: u64, content: IVec) -> Result<()> {
+        let mut inode_attr = self.get_inode_attr(inode)?;
+        inode_attr.size = content.len() as u64;
+        inode_attr.last_modified = time_now();
+        inode_attr.last_metadata_changed = time_now();
+        let inode_attr: IVec = inode_attr.into();
+        let inode_key = u64_to_ivec(inode);
+        let mut batch = Batch::default();
+        batch.insert(Inode, &inode_key, inode_attr);
+        batch.insert(Data, &inode_key, content);
+        self.backend().apply(batch)?;
+        Ok(())
+    }
+}
+
+// An entry stored under its parent inode, keyed by the name that follows it.
+fn named_entry(entry: Result<(IVec, IVec)>) -> Result<(String, (FileKind, u64))> {
+    let (key, value) = entry?;
+    let name = String::from_utf8(key[8..].to_vec())?;
+    Ok((name, decode_entry(&value)?))
 }
@@ -0,0 +1,115 @@
+use super::{Backend, Batch, Bucket, Store};
+use crate::Result;
+use sled::IVec;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::{Arc, RwLock};
+
+pub type MemoryDb = Store<MemoryBackend>;
+
+type Buckets = HashMap<Bucket, BTreeMap<IVec, IVec>>;
+
+// Keeps the buckets in memory only, for mounts without a data path and for
+// tests. Nothing survives a restart, every watch starts with a full list.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    buckets: Arc<RwLock<Buckets>>,
+}
+
+impl MemoryDb {
+    pub fn new() -> MemoryDb {
+        Store::with_backend(MemoryBackend::default())
+    }
+}
+
+impl Default for MemoryDb {
+    fn default() -> Self {
+        MemoryDb::new()
+    }
+}
+
+impl MemoryBackend {
+    // The lock is taken for every step instead of for the whole scan, so the
+    // caller is free to write to the bucket while it iterates.
+    fn scan(
+        &self,
+        bucket: Bucket,
+        prefix: IVec,
+        mut last: Option<IVec>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> + '_ {
+        std::iter::from_fn(move || {
+            let buckets = self.buckets.read().unwrap();
+            let tree = buckets.get(&bucket)?;
+            let start = match &last {
+                Some(key) => Excluded(key.clone()),
+                None => Included(prefix.clone()),
+            };
+            let (key, value) = tree.range((start, Unbounded)).next()?;
+            if !key.starts_with(&prefix) {
+                return None;
+            }
+            last = Some(key.clone());
+            Some(Ok((key.clone(), value.clone())))
+        })
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn get(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<Option<IVec>> {
+        let buckets = self.buckets.read().unwrap();
+        Ok(buckets
+            .get(&bucket)
+            .and_then(|tree| tree.get(key.as_ref()))
+            .cloned())
+    }
+
+    fn insert(&self, bucket: Bucket, key: impl AsRef<[u8]>, value: impl Into<IVec>) -> Result<()> {
+        let mut buckets = self.buckets.write().unwrap();
+        buckets
+            .entry(bucket)
+            .or_default()
+            .insert(key.as_ref().into(), value.into());
+        Ok(())
+    }
+
+    fn remove(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<()> {
+        if let Some(tree) = self.buckets.write().unwrap().get_mut(&bucket) {
+            tree.remove(key.as_ref());
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        self.scan(bucket, prefix.as_ref().into(), None)
+    }
+
+    fn scan_prefix_after(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+        after: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        self.scan(bucket, prefix.as_ref().into(), Some(after.as_ref().into()))
+    }
+
+    fn apply(&self, batch: Batch) -> Result<()> {
+        let mut buckets = self.buckets.write().unwrap();
+        for (bucket, key, value) in batch.ops() {
+            let tree = buckets.entry(*bucket).or_default();
+            match value {
+                Some(value) => tree.insert(key.clone(), value.clone()),
+                None => tree.remove(key),
+            };
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.buckets.write().unwrap().clear();
+        Ok(())
+    }
+}
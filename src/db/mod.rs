@@ -1,23 +1,32 @@
+pub mod disk;
 pub mod manager;
+pub mod memory;
 pub mod storage;
 pub mod utils;
+pub use disk::*;
+pub use memory::*;
 pub use storage::*;
 
 use crate::fuse::core::DentryAttributes;
 use crate::fuse::core::FileKind;
 use crate::fuse::core::InodeAttributes;
-use crate::ClusterObject;
 use crate::Result;
+use crate::{ClusterObject, ClusterObjectMeta};
 use kube::core::DynamicObject;
-use sled::{IVec, Tree};
+use sled::IVec;
 use std::path::Path;
+use utils::SledInode;
 
-#[derive(Clone, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub enum Bucket {
     RIndex,
     Inode,
     Dentry,
+    Entry,
     Data,
+    Object,
+    Part,
+    WatchState,
 }
 
 pub trait Storage: Sync + Send {
@@ -25,18 +34,87 @@ pub trait Storage: Sync + Send {
     fn get(&self, cluster_obj: ClusterObject) -> Result<Option<DynamicObject>>;
     fn update(&self, cluster_obj: ClusterObject) -> Result<()>;
     fn delete(&self, cluster_obj: ClusterObject) -> Result<()>;
-    fn get_bucket(&self, name: Bucket) -> &Tree;
     fn has(&self, cluster_obj: &ClusterObject) -> Result<bool>;
+    fn list(&self, meta: &ClusterObjectMeta) -> Result<Vec<DynamicObject>>;
+    fn get_watch_state(&self, key: &str) -> Result<Option<String>>;
+    fn set_watch_state(&self, key: &str, value: &str) -> Result<()>;
+    fn watch_state_keys(&self, prefix: &str) -> Result<Vec<String>>;
+    fn remove_watch_state(&self, prefix: &str) -> Result<()>;
+    fn reset_cluster(&self, cluster: &str) -> Result<()>;
+    fn unmount_cluster(&self, cluster: &str) -> Result<()>;
+    fn reset(&self) -> Result<()>;
 }
 
 pub trait FSManger: Sync + Send {
     fn mount_dir(&self, path: impl AsRef<Path>, parent_inode: u64) -> Result<u64>;
     fn mount_file(&self, path: impl AsRef<Path>, parent_inode: u64, content: IVec) -> Result<u64>;
     fn edit_file(&self, path: impl AsRef<Path>, content: IVec) -> Result<()>;
+    fn unmount(&self, path: impl AsRef<Path>) -> Result<()>;
+    fn next_inode(&self, key: &str) -> Result<SledInode>;
     fn join_dir(&self, parent_inode: u64, inode: u64, name: String, kind: FileKind) -> Result<()>;
+    fn leave_dir(&self, parent_inode: u64, name: String) -> Result<()>;
     fn get_dentry(&self, inode: u64) -> Result<DentryAttributes>;
+    fn get_entry(&self, parent_inode: u64, name: &str) -> Result<Option<(FileKind, u64)>>;
+    fn entries(&self, inode: u64) -> impl Iterator<Item = Result<(String, (FileKind, u64))>>;
+    fn entries_after(
+        &self,
+        inode: u64,
+        name: &str,
+    ) -> impl Iterator<Item = Result<(String, (FileKind, u64))>>;
+    fn is_empty_dir(&self, inode: u64) -> Result<bool>;
     fn get_inode_attr(&self, inode: u64) -> Result<InodeAttributes>;
     fn update_inode(&self, inode: u64, attr: InodeAttributes) -> Result<()>;
     fn get_inode(&self, key: String) -> Result<u64>;
     fn get_data(&self, inode: u64) -> Result<IVec>;
+    fn set_data(&self, inode: u64, content: IVec) -> Result<()>;
+    fn get_path(&self, inode: u64) -> Result<String>;
+    fn get_part(&self, inode: u64) -> Result<Option<(u64, String)>>;
+}
+
+// Raw key value access to the buckets. The directory tree and object index
+// are built on top of it by Store, so every backend mounts the same tree.
+pub trait Backend: Clone + Sync + Send + 'static {
+    fn get(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<Option<IVec>>;
+    fn insert(&self, bucket: Bucket, key: impl AsRef<[u8]>, value: impl Into<IVec>) -> Result<()>;
+    fn remove(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<()>;
+    // Keys come in ascending order.
+    fn scan_prefix(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>>;
+    // Same as scan_prefix, but starts right after the given key.
+    fn scan_prefix_after(
+        &self,
+        bucket: Bucket,
+        prefix: impl AsRef<[u8]>,
+        after: impl AsRef<[u8]>,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>>;
+    // Either all writes of the batch are applied or none of them.
+    fn apply(&self, batch: Batch) -> Result<()>;
+    fn clear(&self) -> Result<()>;
+
+    fn contains_key(&self, bucket: Bucket, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.get(bucket, key)?.is_some())
+    }
+}
+
+#[derive(Default)]
+pub struct Batch {
+    ops: Vec<(Bucket, IVec, Option<IVec>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, bucket: Bucket, key: impl AsRef<[u8]>, value: impl Into<IVec>) {
+        self.ops
+            .push((bucket, key.as_ref().into(), Some(value.into())));
+    }
+
+    pub fn remove(&mut self, bucket: Bucket, key: impl AsRef<[u8]>) {
+        self.ops.push((bucket, key.as_ref().into(), None));
+    }
+
+    pub fn ops(&self) -> &[(Bucket, IVec, Option<IVec>)] {
+        &self.ops
+    }
 }
@@ -1,93 +1,263 @@
 use super::Bucket::*;
-use super::{Bucket, FSManger, Storage};
+use super::{Backend, FSManger, Storage};
 use crate::db::utils::*;
 use crate::error::Error::MockParentDirError;
-use crate::{ClusterObject, Result};
-use kube::core::DynamicObject;
+use crate::fuse::core::FileKind;
+use crate::view::{alternate_paths, alternates, dir_view, is_secret, view_dir, SECRET_MODE};
+use crate::{ClusterObject, ClusterObjectMeta, Result};
+use kube::core::{ApiResource, DynamicObject};
 use kube::discovery::Scope::*;
-use sled::{Db, IVec, Tree};
-use std::{collections::HashMap, path::Path};
+use sled::IVec;
+use std::path::Path;
+use tracing::warn;
 
-impl AsRef<[u8]> for Bucket {
-    fn as_ref(&self) -> &[u8] {
-        match self {
-            RIndex => "reverse-index".as_bytes(),
-            Inode => "inode".as_bytes(),
-            Dentry => "dentry".as_bytes(),
-            Data => "data".as_bytes(),
-        }
-    }
-}
-
-#[allow(dead_code)]
+// The directory tree and object index of kufu, kept in the buckets of the backend.
 #[derive(Clone)]
-pub struct SledDb {
-    db: Db,
-    buckets: HashMap<Bucket, Tree>,
+pub struct Store<B> {
+    backend: B,
 }
 
-#[allow(dead_code)]
-impl SledDb {
-    pub fn new(path: impl AsRef<Path>) -> Result<SledDb> {
-        let db = sled::open(path)?;
-        clean_one_time_buckets(&db)?;
-        Ok(SledDb {
-            db: db.clone(),
-            buckets: HashMap::from([
-                (Bucket::RIndex, db.open_tree(Bucket::RIndex)?),
-                (Bucket::Inode, db.open_tree(Bucket::Inode)?),
-                (Bucket::Dentry, db.open_tree(Bucket::Dentry)?),
-                (Bucket::Data, db.open_tree(Bucket::Data)?),
-            ]),
-        })
+impl<B: Backend> Store<B> {
+    pub fn with_backend(backend: B) -> Store<B> {
+        Store { backend }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
     }
 
     pub fn mount_gvr(&self, cluster_obj: &ClusterObject) -> Result<u64> {
         let parent_inode = self.mount_gvk(cluster_obj)?;
         let key = get_resource_full_key(cluster_obj);
         let value: IVec = (cluster_obj).try_into()?;
-        let file_key = format!("{}.yaml", &key);
-        match cluster_obj.scope() {
-            Namespaced => self.mount_file(&file_key, parent_inode, value),
-            Cluster => {
-                self.mount_dir(&key, parent_inode)?;
-                self.mount_file(&file_key, parent_inode, value)
+        let file_key = get_resource_file_key(cluster_obj);
+        if is_namespace(cluster_obj) {
+            self.mount_dir(&key, parent_inode)?;
+        }
+        if self.backend.contains_key(RIndex, &file_key)? {
+            warn!(
+                "{} of another object gives way to the object file",
+                file_key
+            );
+            self.unmount(&file_key)?;
+        }
+        let inode = self.mount_file(&file_key, parent_inode, value)?;
+        if is_secret(cluster_obj.meta) {
+            self.make_private(inode, SECRET_MODE)?;
+        }
+        self.mount_alternates(cluster_obj, parent_inode, inode)?;
+        self.mount_view_dir(cluster_obj, inode)?;
+        self.backend.insert(
+            Object,
+            get_resource_index_key(cluster_obj),
+            file_key.as_bytes(),
+        )?;
+        Ok(inode)
+    }
+
+    // Removes the object from the tree, kind directories left empty go with it and
+    // so does a namespace directory whose namespace object is gone as well.
+    pub fn unmount_gvr(&self, cluster_obj: &ClusterObject) -> Result<()> {
+        if !self.has(cluster_obj)? {
+            return Ok(());
+        }
+        self.backend
+            .remove(Object, get_resource_index_key(cluster_obj))?;
+        let file_key = get_resource_file_key(cluster_obj);
+        let source = self.get_inode(file_key.clone())?;
+        for (path, part) in alternate_paths(cluster_obj) {
+            if !self.is_taken(&path, source, false, part)? {
+                self.unmount(path)?;
+            }
+        }
+        if let Some(dir) = view_dir(cluster_obj) {
+            if !self.is_taken(&dir, source, true, false)? {
+                self.unmount(dir)?;
             }
         }
+        self.unmount(file_key)?;
+        if is_namespace(cluster_obj) {
+            self.unmount(get_resource_full_key(cluster_obj))?;
+        }
+        // the namespace root stays even without namespaces, namespaced kinds
+        // mount below it
+        let parent_path = get_parent_resource_full_key(cluster_obj);
+        let ns_root = get_namespace_root_key(&cluster_obj.meta.cluster);
+        let mut dir = get_resource_api_key(cluster_obj);
+        while dir != parent_path && dir != ns_root && self.is_empty_path(&dir)? {
+            self.unmount(&dir)?;
+            dir = into_string(Path::new(&dir).parent().unwrap());
+        }
+        if let Namespaced = cluster_obj.scope() {
+            let extension = cluster_obj.meta.view.format().extension();
+            let ns_file = format!("{}.{}", &parent_path, extension);
+            if self.is_empty_path(&parent_path)? && !self.backend.contains_key(RIndex, ns_file)? {
+                self.unmount(&parent_path)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn update_gvr(&self, cluster_obj: ClusterObject) -> Result<()> {
-        let key = get_resource_full_key(&cluster_obj);
-        let value: IVec = (&cluster_obj).try_into()?;
-        self.edit_file(key, value)
+    fn is_empty_path(&self, path: &str) -> Result<bool> {
+        match self.backend.get(RIndex, path)? {
+            Some(inode) => self.is_empty_dir(ivec_to_u64(&inode)),
+            None => Ok(false),
+        }
+    }
+
+    pub fn update_gvr(&self, cluster_obj: &ClusterObject) -> Result<()> {
+        let key = get_resource_file_key(cluster_obj);
+        let value: IVec = cluster_obj.try_into()?;
+        let inode = self.get_inode(key)?;
+        self.set_data(inode, value)?;
+        let parent_inode = self.get_inode(get_resource_api_key(cluster_obj))?;
+        self.mount_alternates(cluster_obj, parent_inode, inode)?;
+        self.mount_view_dir(cluster_obj, inode)
+    }
+
+    // Dotted names let the paths of two objects meet, app.yaml is the file of
+    // app and the view directory of app.yaml, web.neat.yaml is the file of
+    // web.neat and the neat file of web. Object files always get their path,
+    // alternate files and view directories are skipped while another object
+    // holds theirs and show up with the next update once it is free again.
+    fn mount_alternates(
+        &self,
+        cluster_obj: &ClusterObject,
+        parent: u64,
+        source: u64,
+    ) -> Result<()> {
+        for file in alternates(cluster_obj)? {
+            if self.is_taken(&file.path, source, false, file.part.is_some())? {
+                warn!("{} is skipped, another object holds the path", file.path);
+                continue;
+            }
+            if let Some(inode) = self.backend.get(RIndex, &file.path)? {
+                self.set_data(ivec_to_u64(&inode), file.content)?;
+                continue;
+            }
+            let inode = self.mount_file(&file.path, parent, file.content)?;
+            if let Some(mode) = file.mode {
+                self.make_private(inode, mode)?;
+            }
+            if let Some(part) = file.part {
+                self.backend
+                    .insert(Part, u64_to_ivec(inode), encode_part(source, &part))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether another object holds the path of an alternate file or the view
+    // directory of the object with the source inode. Files of objects and neat
+    // files carry the extension of the first format, so only a view directory
+    // can take the path of a file in another format.
+    fn is_taken(&self, path: &str, source: u64, dir: bool, part: bool) -> Result<bool> {
+        let inode = match self.backend.get(RIndex, path)? {
+            Some(inode) => ivec_to_u64(&inode),
+            None => return Ok(false),
+        };
+        if (self.get_inode_attr(inode)?.kind == FileKind::Directory) != dir {
+            return Ok(true);
+        }
+        Ok(match self.get_part(inode)? {
+            Some((owner, _)) => !part || owner != source,
+            None => part,
+        })
+    }
+
+    // Mounts the files of the view directory next to the file of the object,
+    // only changed files are written so their mtime follows the object. Files
+    // the object no longer has are removed.
+    fn mount_view_dir(&self, cluster_obj: &ClusterObject, source: u64) -> Result<()> {
+        let dir = match view_dir(cluster_obj) {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if self.is_taken(&dir, source, true, false)? {
+            warn!("{} is skipped, another object holds the path", dir);
+            return Ok(());
+        }
+        let dir_inode = self.mount_dir(&dir, self.get_inode(get_resource_api_key(cluster_obj))?)?;
+        let mut stale = self.files_below(&dir)?;
+        for file in dir_view(cluster_obj)? {
+            let path = format!("{}/{}", dir, file.path);
+            stale.retain(|p| p != &path);
+            if let Some(inode) = self.backend.get(RIndex, &path)? {
+                let inode = ivec_to_u64(&inode);
+                if self.get_data(inode)? != file.content {
+                    self.set_data(inode, file.content)?;
+                }
+                continue;
+            }
+            let parent = match file.path.rsplit_once('/') {
+                Some((sub, _)) => self.mount_dir(format!("{}/{}", dir, sub), dir_inode)?,
+                None => dir_inode,
+            };
+            let inode = self.mount_file(&path, parent, file.content)?;
+            if let Some(mode) = file.mode {
+                self.make_private(inode, mode)?;
+            }
+            if let Some(part) = file.part {
+                self.backend
+                    .insert(Part, u64_to_ivec(inode), encode_part(source, &part))?;
+            }
+        }
+        for path in stale {
+            self.unmount(path)?;
+        }
+        Ok(())
+    }
+
+    // Only the user kufu runs as may access the file, see FsInner::check_access.
+    fn make_private(&self, inode: u64, mode: u16) -> Result<()> {
+        let mut attr = self.get_inode_attr(inode)?;
+        attr.mode = mode;
+        attr.uid = unsafe { libc::getuid() };
+        attr.gid = unsafe { libc::getgid() };
+        self.update_inode(inode, attr)
+    }
+
+    fn files_below(&self, dir: &str) -> Result<Vec<String>> {
+        let mut files = vec![];
+        for entry in self.entries(self.get_inode(dir.to_string())?) {
+            let (name, (kind, _)) = entry?;
+            let path = format!("{}/{}", dir, name);
+            match kind {
+                FileKind::Directory => files.extend(self.files_below(&path)?),
+                _ => files.push(path),
+            }
+        }
+        Ok(files)
     }
 
     pub fn mount_gvk(&self, cluster_obj: &ClusterObject) -> Result<u64> {
-        let api_path = get_resource_api_key(cluster_obj);
         let parent_path = get_parent_resource_full_key(cluster_obj);
-        let parent_inode = if self.get_bucket(RIndex).contains_key(parent_path.clone())? {
+        let parent_inode = if self.backend.contains_key(RIndex, parent_path.clone())? {
             let p_inode = self
-                .get_bucket(RIndex)
-                .get(get_parent_resource_full_key(cluster_obj))?
+                .backend
+                .get(RIndex, get_parent_resource_full_key(cluster_obj))?
                 .unwrap();
             ivec_to_u64(&p_inode)
         } else {
             match cluster_obj.scope() {
-                Namespaced => {
-                    // TODO: replace default to cluster
-                    self.mount_dir(
-                        parent_path.clone(),
-                        self.get_inode("default/namespace".to_string())?,
-                    )?
-                }
-                Cluster => return Err(MockParentDirError(api_path.clone())),
+                Namespaced => self.mount_dir(
+                    parent_path.clone(),
+                    self.get_inode(get_namespace_root_key(&cluster_obj.meta.cluster))?,
+                )?,
+                Cluster => return Err(MockParentDirError(get_resource_api_key(cluster_obj))),
             }
         };
-        self.mount_dir(api_path, parent_inode)
+        let mut path = parent_path;
+        let mut inode = parent_inode;
+        for dir in get_kind_path(cluster_obj).split('/') {
+            path = format!("{}/{}", path, dir);
+            inode = self.mount_dir(&path, inode)?;
+        }
+        Ok(inode)
     }
 }
 
-impl Storage for SledDb {
+impl<B: Backend> Storage for Store<B> {
     fn add(&self, cluster_obj: ClusterObject) -> Result<()> {
         if self.has(&cluster_obj)? {
             return self.update(cluster_obj);
@@ -97,35 +267,113 @@ impl Storage for SledDb {
     }
 
     fn update(&self, cluster_obj: ClusterObject) -> Result<()> {
-        let key = get_resource_full_key(&cluster_obj);
-        let value: IVec = (&cluster_obj).try_into()?;
-        self.get_bucket(Data).insert(key, value)?;
-        Ok(())
+        self.update_gvr(&cluster_obj)
     }
 
     fn get(&self, cluster_obj: ClusterObject) -> Result<Option<DynamicObject>> {
-        let key = get_resource_full_key(&cluster_obj);
-        let value = self.buckets.get(&Data).unwrap().get(key)?;
+        if !self.has(&cluster_obj)? {
+            return Ok(None);
+        }
+        let key = get_resource_file_key(&cluster_obj);
+        let inode = match self.backend.get(RIndex, key)? {
+            Some(inode) => inode,
+            None => return Ok(None),
+        };
+        let value = self.backend.get(Data, inode)?;
         match value {
-            Some(v) => Ok(Some(serde_yaml::from_slice(v.as_ref())?)),
+            Some(v) => Ok(Some(cluster_obj.meta.view.format().parse(&v)?)),
             None => Ok(None),
         }
     }
 
     fn delete(&self, cluster_obj: ClusterObject) -> Result<()> {
-        let key = get_resource_full_key(&cluster_obj);
-        self.get_bucket(Data).remove(key)?;
-        Ok(())
+        self.unmount_gvr(&cluster_obj)
     }
 
-    fn get_bucket(&self, name: Bucket) -> &Tree {
-        self.buckets.get(&name).unwrap()
+    // Objects are read back as they are mounted, so redacted values stay
+    // redacted. Objects whose file is gone only carry namespace and name.
+    fn list(&self, meta: &ClusterObjectMeta) -> Result<Vec<DynamicObject>> {
+        let prefix = get_resource_index_prefix(meta);
+        let ar = ApiResource::from_gvk(&meta.gvk);
+        let mut objs = vec![];
+        for item in self.backend.scan_prefix(Object, &prefix) {
+            let (key, file_key) = item?;
+            let key = String::from_utf8(key.to_vec())?;
+            let data = match self.backend.get(RIndex, file_key)? {
+                Some(inode) => self.backend.get(Data, inode)?,
+                None => None,
+            };
+            if let Some(data) = data {
+                objs.push(meta.view.format().parse(&data)?);
+                continue;
+            }
+            let (namespace, name) = key[prefix.len()..].split_once('/').unwrap();
+            let obj = DynamicObject::new(name, &ar);
+            objs.push(match namespace {
+                "" => obj,
+                ns => obj.within(ns),
+            });
+        }
+        Ok(objs)
     }
 
     fn has(&self, cluster_obj: &ClusterObject) -> Result<bool> {
-        let exist = self
-            .get_bucket(RIndex)
-            .contains_key(&get_resource_full_key(cluster_obj))?;
-        Ok(exist)
+        self.backend
+            .contains_key(Object, get_resource_index_key(cluster_obj))
+    }
+
+    fn get_watch_state(&self, key: &str) -> Result<Option<String>> {
+        match self.backend.get(WatchState, key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_watch_state(&self, key: &str, value: &str) -> Result<()> {
+        self.backend.insert(WatchState, key, value)?;
+        Ok(())
+    }
+
+    fn watch_state_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        for item in self.backend.scan_prefix(WatchState, prefix) {
+            keys.push(String::from_utf8(item?.0.to_vec())?);
+        }
+        Ok(keys)
+    }
+
+    // Forgets where the streams under prefix stopped, they are listed again.
+    fn remove_watch_state(&self, prefix: &str) -> Result<()> {
+        for item in self.backend.scan_prefix(WatchState, prefix) {
+            self.backend.remove(WatchState, item?.0)?;
+        }
+        Ok(())
+    }
+
+    // Remounts an empty cluster directory, every kind is listed again.
+    fn reset_cluster(&self, cluster: &str) -> Result<()> {
+        let parent = self
+            .get_dentry(self.get_inode(cluster.to_string())?)?
+            .parent;
+        self.unmount_cluster(cluster)?;
+        self.mount_dir(cluster, parent)?;
+        Ok(())
+    }
+
+    // Drops the whole directory tree of a cluster along with its object index
+    // and watch state.
+    fn unmount_cluster(&self, cluster: &str) -> Result<()> {
+        for bucket in [Object, WatchState] {
+            let prefix = format!("{}/", cluster);
+            for item in self.backend.scan_prefix(bucket, prefix) {
+                self.backend.remove(bucket, item?.0)?;
+            }
+        }
+        self.backend.remove(WatchState, cluster)?;
+        self.unmount(cluster)
+    }
+
+    fn reset(&self) -> Result<()> {
+        self.backend.clear()
     }
 }
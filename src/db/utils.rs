@@ -1,10 +1,9 @@
-use crate::db::Bucket;
-use crate::{ClusterObject, Result, INODE_NUM};
-use kube::core::DynamicObject;
+use crate::config::Layout;
+use crate::error::Error::DecodeMetadataFail;
+use crate::{ClusterObject, ClusterObjectMeta, Result};
 use kube::discovery::Scope;
-use sled::{Db, IVec};
+use sled::IVec;
 use std::path::Path;
-use std::sync::atomic::Ordering;
 
 pub type SledInode = (u64, IVec);
 
@@ -18,43 +17,95 @@ pub fn ivec_to_u64(value: &IVec) -> u64 {
     u64::from_be_bytes(raw[0..8].try_into().unwrap())
 }
 
-pub fn handle_next_inode() -> SledInode {
-    let inode_c = INODE_NUM.fetch_add(1, Ordering::SeqCst);
-    (inode_c, u64_to_ivec(inode_c))
+// Entries of a directory share its inode as key prefix, followed by their name.
+pub fn entry_key(parent: u64, name: &str) -> Vec<u8> {
+    let mut key = parent.to_be_bytes().to_vec();
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+// A part of a split view points back to the file of its object.
+pub fn encode_part(source: u64, part: &str) -> Vec<u8> {
+    entry_key(source, part)
+}
+
+pub fn decode_part(value: &[u8]) -> Result<(u64, String)> {
+    if value.len() < 8 {
+        return Err(DecodeMetadataFail("view part is too short".to_string()));
+    }
+    let source = u64::from_be_bytes(value[..8].try_into().unwrap());
+    Ok((source, String::from_utf8(value[8..].to_vec())?))
+}
+
+pub const ROOT_INODE: u64 = 1;
+pub const INODE_MASK: u64 = u64::MAX >> 1;
+
+// FNV-1a of the path, it has to stay the same across releases so no std hasher.
+pub fn path_inode(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    hash & INODE_MASK
 }
 
 pub fn get_resource_full_key(cluster_obj: &ClusterObject) -> String {
-    let kind = &cluster_obj.meta.gvk.kind;
     let name = cluster_obj.obj.metadata.name.as_ref().unwrap();
-    let cluster = &cluster_obj.meta.cluster;
-    let namespace = cluster_obj.obj.metadata.namespace.as_ref();
-    match cluster_obj.scope() {
-        Scope::Namespaced => {
-            format!(
-                "{}/namespace/{}/{}/{}",
-                cluster,
-                namespace.unwrap(),
-                kind,
-                name
-            )
-        }
-        Scope::Cluster => {
-            format!("{}/{}/{}", cluster, kind, name)
-        }
-    }
+    format!("{}/{}", get_resource_api_key(cluster_obj), name)
+}
+
+pub fn get_resource_file_key(cluster_obj: &ClusterObject) -> String {
+    let extension = cluster_obj.meta.view.format().extension();
+    format!("{}.{}", get_resource_full_key(cluster_obj), extension)
+}
+
+// Objects of one kind in a cluster share this prefix in the object index.
+pub fn get_resource_index_prefix(meta: &ClusterObjectMeta) -> String {
+    format!(
+        "{}/{}/{}/",
+        meta.cluster,
+        meta.gvk.api_version(),
+        meta.gvk.kind
+    )
+}
+
+pub fn get_resource_index_key(cluster_obj: &ClusterObject) -> String {
+    let namespace = cluster_obj
+        .obj
+        .metadata
+        .namespace
+        .as_deref()
+        .unwrap_or_default();
+    let name = cluster_obj.obj.metadata.name.as_ref().unwrap();
+    format!(
+        "{}{}/{}",
+        get_resource_index_prefix(cluster_obj.meta),
+        namespace,
+        name
+    )
 }
 
 pub fn get_resource_api_key(cluster_obj: &ClusterObject) -> String {
-    let kind = &cluster_obj.meta.gvk.kind.to_ascii_lowercase();
-    let cluster = &cluster_obj.meta.cluster;
-    match cluster_obj.scope() {
-        Scope::Namespaced => {
-            let namespace = cluster_obj.obj.metadata.namespace.as_ref().unwrap();
-            format!("{}/namespace/{}/{}", cluster, namespace, kind)
-        }
-        Scope::Cluster => {
-            format!("{}/{}", cluster, kind)
-        }
+    format!(
+        "{}/{}",
+        get_parent_resource_full_key(cluster_obj),
+        get_kind_path(cluster_obj)
+    )
+}
+
+// The directories of the kind below its namespace or cluster directory, which
+// may be nested depending on the layout.
+pub fn get_kind_path(cluster_obj: &ClusterObject) -> String {
+    let meta = cluster_obj.meta;
+    let kind = meta.gvk.kind.to_ascii_lowercase();
+    if is_namespace(cluster_obj) {
+        return kind;
+    }
+    match (meta.view.layout, meta.gvk.group.as_str()) {
+        (Layout::Kind, _) => kind,
+        (Layout::ResourceGroup, "") => meta.plural.clone(),
+        (Layout::ResourceGroup, group) => format!("{}.{}", meta.plural, group),
+        (Layout::GroupVersionKind, "") => format!("core/{}/{}", meta.gvk.version, kind),
+        (Layout::GroupVersionKind, group) => format!("{}/{}/{}", group, meta.gvk.version, kind),
     }
 }
 
@@ -63,19 +114,23 @@ pub fn get_parent_resource_full_key(cluster_obj: &ClusterObject) -> String {
     match cluster_obj.scope() {
         Scope::Namespaced => {
             let namespace = cluster_obj.obj.metadata.namespace.as_ref().unwrap();
-            format!("{}/namespace/{}", cluster, namespace)
-        }
-        Scope::Cluster => {
-            format!("{}", cluster)
+            get_namespace_key(cluster, namespace)
         }
+        Scope::Cluster => cluster.to_string(),
     }
 }
 
-pub fn clean_one_time_buckets(db: &Db) -> Result<()> {
-    db.drop_tree(Bucket::RIndex)?;
-    db.drop_tree(Bucket::Inode)?;
-    db.drop_tree(Bucket::Dentry)?;
-    Ok(())
+pub fn is_namespace(cluster_obj: &ClusterObject) -> bool {
+    let gvk = &cluster_obj.meta.gvk;
+    gvk.group.is_empty() && gvk.kind == "Namespace"
+}
+
+pub fn get_namespace_root_key(cluster: &str) -> String {
+    format!("{}/namespace", cluster)
+}
+
+pub fn get_namespace_key(cluster: &str, namespace: &str) -> String {
+    format!("{}/{}", get_namespace_root_key(cluster), namespace)
 }
 
 pub fn extract_name(path: &Path) -> String {
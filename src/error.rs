@@ -1,4 +1,9 @@
-use kube::{config::KubeconfigError, core::gvk, runtime::watcher};
+use kube::{config::KubeconfigError, core::gvk, error::ErrorResponse, runtime::watcher};
+use libc::{
+    c_int, EACCES, EAGAIN, EBADF, ECONNREFUSED, EEXIST, EFBIG, EHOSTUNREACH, EINVAL, EIO, ENOENT,
+    ENOTEMPTY, EPERM, ESTALE, ETIMEDOUT,
+};
+use std::io;
 use std::string::FromUtf8Error;
 use thiserror::Error;
 use tokio::task;
@@ -29,6 +34,9 @@ pub enum Error {
     #[error("serialize dynamicObject to yaml failed: {0}")]
     SerializeDynamicObject2Yaml(#[from] serde_yaml::Error),
 
+    #[error("serialize dynamicObject to json failed: {0}")]
+    SerializeDynamicObject2Json(#[from] serde_json::Error),
+
     #[error("sled transaction failed: {0}")]
     SledTransactionError(#[from] sled::transaction::TransactionError),
 
@@ -38,6 +46,9 @@ pub enum Error {
     #[error("look up inode attribute failed: {0}")]
     InodeAttrNotFound(u64),
 
+    #[error("decode metadata failed: {0}")]
+    DecodeMetadataFail(String),
+
     #[error("look up dentry attribute failed: {0}")]
     DentryAttrNotFound(u64),
 
@@ -47,9 +58,135 @@ pub enum Error {
     #[error("mock parent dir for {0} failed")]
     MockParentDirError(String),
 
+    #[error("look up inode of path {0} failed")]
+    PathNotFound(String),
+
     #[error("look up cluster object data {0} failed")]
     ClusterObjectDataNotFound(u64),
 
     #[error("covert ivec to string failed: {0}")]
     ConvertIVecToStringError(#[from] FromUtf8Error),
+
+    #[error("invalid cluster object: {0}")]
+    InvalidClusterObject(String),
+
+    #[error("look up write buffer for file handle {0} failed")]
+    WriteHandleNotFound(u64),
+
+    #[error("unsupported file name: {0}, only files of the mounted formats can be created")]
+    UnsupportedFileName(String),
+
+    #[error("unsupported directory: {0}, only namespaces can be created or removed")]
+    UnsupportedDirectory(String),
+
+    #[error("directory {0} is not empty")]
+    DirectoryNotEmpty(String),
+
+    #[error("cluster {0} is not mounted")]
+    ClusterNotFound(String),
+
+    #[error("{0} is a part of a split view, it can only be edited")]
+    UnsupportedViewPart(String),
+
+    #[error("inode {0} can only be truncated through a handle opened for writing")]
+    TruncateWithoutHandle(u64),
+
+    #[error(
+        "namespace {0} is not deleted, enable delete-namespaces to remove namespace directories"
+    )]
+    NamespaceDeletionDisabled(String),
+
+    #[error("file name {0} is not valid utf-8")]
+    NonUtf8Name(String),
+
+    #[error("inode {0} is only accessible to the mounting user")]
+    AccessDenied(u64),
+
+    #[error("file size {0} exceeds the limit of {1} bytes")]
+    FileTooLarge(u64, usize),
+
+    #[error("invalid file offset: {0}")]
+    InvalidOffset(i64),
+}
+
+impl Error {
+    // Every variant is listed, so a new one has to pick the errno the fuse
+    // callbacks reply with.
+    pub fn errno(&self) -> c_int {
+        match self {
+            Error::BuildKubeClientFail(e) => kube_errno(e),
+            Error::WatchEventFail(e) => match e {
+                watcher::Error::InitialListFailed(e)
+                | watcher::Error::WatchStartFailed(e)
+                | watcher::Error::WatchFailed(e) => kube_errno(e),
+                watcher::Error::WatchError(resp) => api_errno(resp),
+                watcher::Error::NoResourceVersion | watcher::Error::TooManyObjects => EIO,
+            },
+            Error::ReadKubeConfigFail(_) | Error::LoadKubeconfigFail(_) => EIO,
+            Error::ParseGVKFail(_) => EINVAL,
+            Error::RuntimeJoinTaskFail(_) => EIO,
+            Error::StoreDynamicObjectFailed(_) | Error::SledTransactionError(_) => EIO,
+            Error::DecodeMetadataFail(_) | Error::ConvertIVecToStringError(_) => EIO,
+            Error::SerializeDynamicObject2Yaml(_)
+            | Error::SerializeDynamicObject2Json(_)
+            | Error::InvalidClusterObject(_) => EINVAL,
+            Error::ConvertOsStrError(_) | Error::NonUtf8Name(_) | Error::InvalidOffset(_) => EINVAL,
+            Error::InodeAttrNotFound(_)
+            | Error::DentryAttrNotFound(_)
+            | Error::ChildEntryNotFound(_, _)
+            | Error::PathNotFound(_)
+            | Error::ClusterObjectDataNotFound(_)
+            | Error::MockParentDirError(_)
+            | Error::ClusterNotFound(_) => ENOENT,
+            Error::WriteHandleNotFound(_) => EBADF,
+            Error::UnsupportedFileName(_) | Error::AccessDenied(_) => EACCES,
+            Error::UnsupportedDirectory(_) | Error::UnsupportedViewPart(_) => EPERM,
+            Error::TruncateWithoutHandle(_) | Error::NamespaceDeletionDisabled(_) => EPERM,
+            Error::DirectoryNotEmpty(_) => ENOTEMPTY,
+            Error::FileTooLarge(_, _) => EFBIG,
+        }
+    }
+}
+
+fn kube_errno(e: &kube::Error) -> c_int {
+    match e {
+        kube::Error::Api(resp) => api_errno(resp),
+        kube::Error::Auth(_) => EACCES,
+        kube::Error::Discovery(_) => ENOENT,
+        kube::Error::BuildRequest(_) | kube::Error::SerdeError(_) => EINVAL,
+        _ => connect_errno(e),
+    }
+}
+
+fn api_errno(resp: &ErrorResponse) -> c_int {
+    match (resp.code, resp.reason.as_str()) {
+        (409, "AlreadyExists") => EEXIST,
+        // the object changed in the meantime, writing it again may succeed
+        (409, _) | (429, _) => EAGAIN,
+        (400 | 422, _) => EINVAL,
+        (401 | 403, _) => EACCES,
+        (404, _) => ENOENT,
+        (405, _) => EPERM,
+        (410, _) => ESTALE,
+        (504, _) => ETIMEDOUT,
+        _ => EIO,
+    }
+}
+
+// The cluster could not be reached, the cause is the io error at the bottom
+// of the client error.
+fn connect_errno(e: &kube::Error) -> c_int {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::ConnectionRefused => ECONNREFUSED,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => EHOSTUNREACH,
+                io::ErrorKind::TimedOut => ETIMEDOUT,
+                _ => e.raw_os_error().unwrap_or(EIO),
+            };
+        }
+        source = e.source();
+    }
+    EIO
 }
@@ -0,0 +1,97 @@
+use crate::config::Format;
+use crate::Result;
+use kube::core::DynamicObject;
+use serde_json::Value;
+use sled::IVec;
+
+// Writes and reads the content of object files. Everything goes through json
+// values, so a new format only has to turn them into bytes and back.
+pub trait Serializer: Sync + Send {
+    // Without the leading dot.
+    fn extension(&self) -> &'static str;
+    fn encode(&self, value: &Value) -> Result<Vec<u8>>;
+    fn decode(&self, data: &[u8]) -> Result<Value>;
+}
+
+pub struct Yaml;
+
+impl Serializer for Yaml {
+    fn extension(&self) -> &'static str {
+        "yaml"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        Ok(serde_yaml::to_string(value)?.into_bytes())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Value> {
+        Ok(serde_yaml::from_slice(data)?)
+    }
+}
+
+pub struct Json;
+
+impl Serializer for Json {
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        let mut data = serde_json::to_vec_pretty(value)?;
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Value> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+pub struct JsonLines;
+
+impl Serializer for JsonLines {
+    fn extension(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        let mut data = serde_json::to_vec(value)?;
+        data.push(b'\n');
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Value> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+impl Format {
+    pub fn serializer(self) -> &'static dyn Serializer {
+        match self {
+            Format::Yaml => &Yaml,
+            Format::Json => &Json,
+            Format::JsonLines => &JsonLines,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.serializer().extension()
+    }
+
+    // Picks the format by the extension of the file name.
+    pub fn of(name: &str) -> Option<Format> {
+        let (_, extension) = name.rsplit_once('.')?;
+        [Format::Yaml, Format::Json, Format::JsonLines]
+            .into_iter()
+            .find(|f| f.extension() == extension)
+    }
+
+    pub fn render(self, obj: &DynamicObject) -> Result<IVec> {
+        let data = self.serializer().encode(&serde_json::to_value(obj)?)?;
+        Ok(data.into())
+    }
+
+    pub fn parse(self, data: &[u8]) -> Result<DynamicObject> {
+        Ok(serde_json::from_value(self.serializer().decode(data)?)?)
+    }
+}
@@ -1,5 +1,4 @@
-use crate::error;
-use serde::{Deserialize, Serialize};
+use crate::error::{self, Error::DecodeMetadataFail};
 use sled::IVec;
 
 use std::{
@@ -11,13 +10,35 @@ const BLOCK_SIZE: u64 = 512;
 
 type Inode = u64;
 
-#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum FileKind {
     File,
     Directory,
     Symlink,
 }
 
+impl From<FileKind> for u8 {
+    fn from(kind: FileKind) -> Self {
+        match kind {
+            FileKind::File => 0,
+            FileKind::Directory => 1,
+            FileKind::Symlink => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for FileKind {
+    type Error = error::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FileKind::File),
+            1 => Ok(FileKind::Directory),
+            2 => Ok(FileKind::Symlink),
+            _ => Err(DecodeMetadataFail(format!("unknown file kind {}", value))),
+        }
+    }
+}
+
 impl From<FileKind> for fuser::FileType {
     fn from(kind: FileKind) -> Self {
         match kind {
@@ -28,9 +49,10 @@ impl From<FileKind> for fuser::FileType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub struct InodeAttributes {
     pub inode: Inode,
+    pub parent: Inode,
     pub open_file_handles: u64, // Ref count of open file handles to this inode
     pub size: u64,
     pub last_accessed: (i64, u32),
@@ -46,9 +68,10 @@ pub struct InodeAttributes {
 }
 
 impl InodeAttributes {
-    pub fn new_file(inode: u64, size: u64) -> InodeAttributes {
+    pub fn new_file(inode: u64, parent: u64, size: u64) -> InodeAttributes {
         InodeAttributes {
             inode,
+            parent,
             open_file_handles: 0,
             size,
             last_accessed: time_now(),
@@ -63,9 +86,10 @@ impl InodeAttributes {
         }
     }
 
-    pub fn new_dict(inode: u64) -> InodeAttributes {
+    pub fn new_dict(inode: u64, parent: u64) -> InodeAttributes {
         InodeAttributes {
             inode,
+            parent,
             open_file_handles: 0,
             size: 0,
             last_accessed: time_now(),
@@ -84,23 +108,61 @@ impl InodeAttributes {
 impl TryFrom<IVec> for InodeAttributes {
     type Error = error::Error;
     fn try_from(value: IVec) -> Result<Self, Self::Error> {
-        let inode_attr: InodeAttributes = serde_yaml::from_slice(&*value)?;
-        Ok(inode_attr)
+        value.as_ref().try_into()
     }
 }
 
 impl TryFrom<&[u8]> for InodeAttributes {
     type Error = error::Error;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let inode_attr: InodeAttributes = serde_yaml::from_slice(&*value)?;
-        Ok(inode_attr)
+        let mut d = Decoder(value);
+        let attr = InodeAttributes {
+            inode: d.u64()?,
+            parent: d.u64()?,
+            open_file_handles: d.u64()?,
+            size: d.u64()?,
+            last_accessed: d.time()?,
+            last_modified: d.time()?,
+            last_metadata_changed: d.time()?,
+            kind: d.u8()?.try_into()?,
+            mode: d.u16()?,
+            hardlinks: d.u32()?,
+            uid: d.u32()?,
+            gid: d.u32()?,
+            xattrs: {
+                let mut xattrs = BTreeMap::new();
+                for _ in 0..d.u32()? {
+                    xattrs.insert(d.bytes()?, d.bytes()?);
+                }
+                xattrs
+            },
+        };
+        d.finish()?;
+        Ok(attr)
     }
 }
 
 impl From<InodeAttributes> for IVec {
     fn from(value: InodeAttributes) -> Self {
-        let obj_data = &*serde_yaml::to_string(&value).unwrap();
-        obj_data.into()
+        let mut e = Encoder::default();
+        e.u64(value.inode);
+        e.u64(value.parent);
+        e.u64(value.open_file_handles);
+        e.u64(value.size);
+        e.time(value.last_accessed);
+        e.time(value.last_modified);
+        e.time(value.last_metadata_changed);
+        e.u8(value.kind.into());
+        e.u16(value.mode);
+        e.u32(value.hardlinks);
+        e.u32(value.uid);
+        e.u32(value.gid);
+        e.u32(value.xattrs.len() as u32);
+        for (key, value) in value.xattrs.iter() {
+            e.bytes(key);
+            e.bytes(value);
+        }
+        e.0.into()
     }
 }
 
@@ -109,7 +171,7 @@ impl From<InodeAttributes> for fuser::FileAttr {
         fuser::FileAttr {
             ino: attrs.inode,
             size: attrs.size,
-            blocks: (attrs.size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            blocks: attrs.size.div_ceil(BLOCK_SIZE),
             atime: system_time_from_time(attrs.last_accessed.0, attrs.last_accessed.1),
             mtime: system_time_from_time(attrs.last_modified.0, attrs.last_modified.1),
             ctime: system_time_from_time(
@@ -129,33 +191,134 @@ impl From<InodeAttributes> for fuser::FileAttr {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// The entries of a directory are not part of it, every entry is a key of its
+// own in the entry bucket, see get_entry and entries of FSManger.
+#[derive(Debug)]
 pub struct DentryAttributes {
     pub parent: u64,
-    pub entries: BTreeMap<String, (FileKind, u64)>,
     pub name: String,
 }
 
 impl From<DentryAttributes> for IVec {
     fn from(value: DentryAttributes) -> Self {
-        let obj_data = &*serde_yaml::to_string(&value).unwrap();
-        obj_data.into()
+        let mut e = Encoder::default();
+        e.u64(value.parent);
+        e.0.extend_from_slice(value.name.as_bytes());
+        e.0.into()
     }
 }
 
 impl TryFrom<&[u8]> for DentryAttributes {
     type Error = error::Error;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let dentry_attr: DentryAttributes = serde_yaml::from_slice(&*value)?;
-        Ok(dentry_attr)
+        let mut d = Decoder(value);
+        let parent = d.u64()?;
+        let name = String::from_utf8(d.0.to_vec())?;
+        Ok(DentryAttributes { parent, name })
     }
 }
 
 impl TryFrom<IVec> for DentryAttributes {
     type Error = error::Error;
     fn try_from(value: IVec) -> Result<Self, Self::Error> {
-        let inode_attr: DentryAttributes = serde_yaml::from_slice(&*value)?;
-        Ok(inode_attr)
+        value.as_ref().try_into()
+    }
+}
+
+// A directory entry is stored as its kind followed by its inode.
+pub fn encode_entry(kind: FileKind, inode: u64) -> IVec {
+    let mut e = Encoder::default();
+    e.u8(kind.into());
+    e.u64(inode);
+    e.0.into()
+}
+
+pub fn decode_entry(value: &[u8]) -> Result<(FileKind, u64), error::Error> {
+    let mut d = Decoder(value);
+    let entry = (d.u8()?.try_into()?, d.u64()?);
+    d.finish()?;
+    Ok(entry)
+}
+
+// Metadata is encoded big endian in a fixed field order, variable sized fields
+// are prefixed with their length.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn time(&mut self, value: (i64, u32)) {
+        self.0.extend_from_slice(&value.0.to_be_bytes());
+        self.u32(value.1);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], error::Error> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], error::Error> {
+        if self.0.len() < len {
+            return Err(DecodeMetadataFail("unexpected end of data".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, error::Error> {
+        Ok(u8::from_be_bytes(self.take()?))
+    }
+
+    fn u16(&mut self) -> Result<u16, error::Error> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, error::Error> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, error::Error> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn time(&mut self) -> Result<(i64, u32), error::Error> {
+        Ok((i64::from_be_bytes(self.take()?), self.u32()?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, error::Error> {
+        let len = self.u32()? as usize;
+        Ok(self.slice(len)?.to_vec())
+    }
+
+    fn finish(&self) -> Result<(), error::Error> {
+        match self.0.len() {
+            0 => Ok(()),
+            n => Err(DecodeMetadataFail(format!("{} trailing bytes", n))),
+        }
     }
 }
 
@@ -1,93 +1,450 @@
-use crate::db::{FSManger, SledDb};
-use crate::error::Error::ChildEntryNotFound;
-use crate::{Result, FILE_HANDLE_NUM};
+use crate::config::{Format, View};
+use crate::db::utils::{
+    extract_name, get_namespace_root_key, get_resource_api_key, get_resource_full_key,
+};
+use crate::db::{FSManger, Storage};
+use crate::error::Error::{
+    AccessDenied, ChildEntryNotFound, DirectoryNotEmpty, FileTooLarge, InodeAttrNotFound,
+    InvalidClusterObject, InvalidOffset, NonUtf8Name, TruncateWithoutHandle, UnsupportedDirectory,
+    UnsupportedFileName, WriteHandleNotFound,
+};
+use crate::fuse::core::{FileKind, InodeAttributes};
+use crate::{ClusterObject, ClusterObjectMeta, Result, FILE_HANDLE_NUM};
 use fuser::{FileAttr, ReplyDirectory};
+use kube::core::DynamicObject;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::str::FromStr;
 use tracing::info;
 
 const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
+// Write buffers are kept in memory until flush, objects are far smaller.
+const MAX_FILE_SIZE: usize = 8 << 20;
 
-pub struct FsInner {
-    pub store: SledDb,
+pub struct FsInner<S> {
+    pub store: S,
+    pub view: View,
+    writes: HashMap<u64, WriteBuffer>,
+    pending: HashMap<u64, PendingFile>,
+    // The offset and name of the last entry each directory handle listed, a
+    // readdir from that offset goes on after the name.
+    listings: HashMap<u64, (i64, String)>,
 }
 
-impl FsInner {
-    pub fn new(store: SledDb) -> FsInner {
-        FsInner { store }
+// A file created by the user which has no cluster object behind it yet, it
+// lives until the last handle is released and the watcher mounts the object.
+struct PendingFile {
+    parent: u64,
+    name: String,
+}
+
+// Content written through an open file handle, kept in memory until flush
+// pushes it to the cluster.
+struct WriteBuffer {
+    inode: u64,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+// The entries read_dir replies with, from the dots on or after a listed name.
+type DirEntries<'a> = Box<dyn Iterator<Item = Result<(String, (FileKind, u64))>> + 'a>;
+
+impl<S: Storage + FSManger> FsInner<S> {
+    pub fn new(store: S, view: View) -> FsInner<S> {
+        FsInner {
+            store,
+            view,
+            writes: HashMap::new(),
+            pending: HashMap::new(),
+            listings: HashMap::new(),
+        }
     }
 
-    pub fn init(&self, mount_point: String, cluster: Vec<String>) -> Result<()> {
-        let mount_point_inode = self.store.mount_dir(mount_point.clone(), 0)?;
-        self.store.mount_dir("default", mount_point_inode)?;
+    // Cluster directories are mounted by the cluster manager. The tree of the
+    // last run is reused unless it was mounted somewhere else, the root has to
+    // stay inode 1.
+    pub fn init(&self, mount_point: String) -> Result<()> {
+        if self.store.get_inode(mount_point.clone()).is_err() {
+            self.store.reset()?;
+        }
+        self.store.mount_dir(mount_point, 0)?;
         Ok(())
     }
 
     pub fn look_up(&self, parent: u64, name: &OsStr) -> Result<FileAttr> {
-        let name = name.to_str().unwrap();
-        let dentry = self.store.get_dentry(parent)?;
-        let entry = dentry.entries.get(name);
-        let inode = match entry {
-            Some(item) => item.1,
-            None => return Err(ChildEntryNotFound(dentry.name, name.to_string())),
+        let name = utf8_name(name)?;
+        let entry = self.store.get_entry(parent, name)?;
+        let pending = self
+            .pending
+            .iter()
+            .find(|(_, f)| f.parent == parent && f.name == name);
+        let inode = match (entry, pending) {
+            (Some(item), _) => item.1,
+            (None, Some((inode, _))) => *inode,
+            (None, None) => {
+                let dentry = self.store.get_dentry(parent)?;
+                return Err(ChildEntryNotFound(dentry.name, name.to_string()));
+            }
         };
-        return self.get_attr(inode);
+        self.get_attr(inode)
     }
 
     pub fn get_attr(&self, inode: u64) -> Result<FileAttr> {
-        let attr = self.store.get_inode_attr(inode)?;
+        let mut attr = if let Some(file) = self.pending(inode) {
+            InodeAttributes::new_file(inode, file.parent, 0)
+        } else {
+            self.store.get_inode_attr(inode)?
+        };
+        if let Some(buffer) = self.writes.values().find(|b| b.inode == inode && b.dirty) {
+            attr.size = buffer.data.len() as u64;
+        }
         Ok(attr.into())
     }
 
-    pub fn read_dir(&self, inode: u64, offset: i64, reply: &mut ReplyDirectory) -> Result<()> {
+    pub fn read_dir(
+        &mut self,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        reply: &mut ReplyDirectory,
+    ) -> Result<()> {
         let dentry = self.store.get_dentry(inode)?;
         info!("success call read_dir, dir: {:?}", dentry);
-        for (index, entry) in dentry.entries.iter().skip(offset as usize).enumerate() {
-            let (name, (kind, inode)) = entry;
+        let parent = match dentry.parent {
+            0 => inode,
+            parent => parent,
+        };
+        // the dots come first, only a stored name can be continued after
+        let after = self
+            .listings
+            .get(&fh)
+            .filter(|(listed, _)| *listed == offset && offset > 2)
+            .map(|(_, name)| name.clone());
+        let entries: DirEntries = match &after {
+            Some(name) => Box::new(self.store.entries_after(inode, name)),
+            None => {
+                let dots = [
+                    Ok((".".to_string(), (FileKind::Directory, inode))),
+                    Ok(("..".to_string(), (FileKind::Directory, parent))),
+                ];
+                Box::new(
+                    dots.into_iter()
+                        .chain(self.store.entries(inode))
+                        .skip(offset as usize),
+                )
+            }
+        };
+        let mut last = None;
+        for (index, entry) in entries.enumerate() {
+            let (name, (kind, inode)) = entry?;
+            let next = offset + index as i64 + 1;
             info!(
                 "reply add offset:{:?}, name: {:?} kind: {:?}",
-                offset,
-                OsString::from_str(name)?.as_os_str(),
+                next,
+                OsString::from_str(&name)?.as_os_str(),
                 kind,
             );
             if reply.add(
-                *inode,
-                offset + index as i64 + 1,
-                (*kind).into(),
-                OsString::from_str(name)?.as_os_str(),
+                inode,
+                next,
+                kind.into(),
+                OsString::from_str(&name)?.as_os_str(),
             ) {
                 break;
             }
+            last = Some((next, name));
+        }
+        if let Some(last) = last {
+            self.listings.insert(fh, last);
         }
         Ok(())
     }
 
+    pub fn release_dir(&mut self, fh: u64) {
+        self.listings.remove(&fh);
+    }
+
     pub fn open_dir(&self, inode: u64, read: bool, write: bool) -> Result<u64> {
-        let mut inode_attr = self.store.get_inode_attr(inode)?;
-        inode_attr.open_file_handles += 1;
-        self.store.update_inode(inode, inode_attr)?;
-        let mut fh = next_file_handle();
-        if read {
-            fh |= FILE_HANDLE_READ_BIT;
+        // handles are not counted in the stored attributes, writing them back
+        // would race with the watcher mounting the directory again
+        self.store.get_inode_attr(inode)?;
+        Ok(new_file_handle(read, write))
+    }
+
+    pub fn create(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr> {
+        let name = utf8_name(name)?;
+        let mounted = Format::of(name)
+            .is_some_and(|f| f == self.view.format() || self.view.alternate_formats().contains(&f));
+        if !mounted {
+            return Err(UnsupportedFileName(name.to_string()));
+        }
+        // same inode the file gets once the watch event mounts it
+        let path = format!("{}/{}", self.store.get_path(parent)?, name);
+        let (inode, _) = self.store.next_inode(&path)?;
+        self.pending.insert(
+            inode,
+            PendingFile {
+                parent,
+                name: name.to_string(),
+            },
+        );
+        self.get_attr(inode)
+    }
+
+    // Returns the cluster the inode is mounted under.
+    pub fn cluster_of(&self, inode: u64) -> Result<String> {
+        let dir = match self.pending(inode) {
+            Some(file) => file.parent,
+            None => match self.store.get_dentry(inode) {
+                Ok(_) => inode,
+                Err(_) => self.store.get_inode_attr(inode)?.parent,
+            },
+        };
+        let path = self.store.get_path(dir)?;
+        Ok(path.split('/').next().unwrap_or_default().to_string())
+    }
+
+    // Returns the cluster whose namespace directory is the given inode.
+    pub fn namespace_root(&self, inode: u64) -> Result<String> {
+        let path = self.store.get_path(inode)?;
+        let cluster = path.split('/').next().unwrap_or_default();
+        if path != get_namespace_root_key(cluster) {
+            return Err(UnsupportedDirectory(path));
+        }
+        Ok(cluster.to_string())
+    }
+
+    pub fn check_empty_dir(&self, inode: u64) -> Result<()> {
+        if !self.store.is_empty_dir(inode)? {
+            return Err(DirectoryNotEmpty(self.store.get_dentry(inode)?.name));
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, inode: u64) {
+        self.pending.remove(&inode);
+    }
+
+    pub fn is_pending(&self, inode: u64) -> bool {
+        self.pending(inode).is_some()
+    }
+
+    // A created file stops being pending once the watcher mounted its object,
+    // from then on it is read from the store like every other file.
+    fn pending(&self, inode: u64) -> Option<&PendingFile> {
+        self.pending
+            .get(&inode)
+            .filter(|_| self.store.get_inode_attr(inode).is_err())
+    }
+
+    // Makes sure a created object would be mounted at the same place the user
+    // created the file, otherwise it shows up somewhere else after the watch event.
+    pub fn check_placement(&self, inode: u64, cluster_obj: &ClusterObject) -> Result<()> {
+        let file = match self.pending(inode) {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let dir = self.store.get_path(file.parent)?;
+        let expect_dir = get_resource_api_key(cluster_obj);
+        let extension = Format::of(&file.name).unwrap_or_default().extension();
+        let expect_name = format!(
+            "{}.{}",
+            extract_name(get_resource_full_key(cluster_obj).as_ref()),
+            extension
+        );
+        if dir != expect_dir || file.name != expect_name {
+            return Err(InvalidClusterObject(format!(
+                "object belongs to {}/{}, but was created as {}/{}",
+                expect_dir, expect_name, dir, file.name
+            )));
+        }
+        Ok(())
+    }
+
+    // Returns the format of an object file, picked by the extension of its name.
+    pub fn format_of(&self, inode: u64) -> Result<Format> {
+        let name = match self.pending(inode) {
+            Some(file) => file.name.clone(),
+            None => self.file_name(inode)?,
+        };
+        Ok(Format::of(&name).unwrap_or(self.view.format()))
+    }
+
+    // Files have no dentry, so the name is looked up in the entries of the parent.
+    fn file_name(&self, inode: u64) -> Result<String> {
+        let parent = self.store.get_inode_attr(inode)?.parent;
+        for entry in self.store.entries(parent) {
+            let (name, (_, ino)) = entry?;
+            if ino == inode {
+                return Ok(name);
+            }
         }
+        Err(InodeAttrNotFound(inode))
+    }
+
+    // The kernel leaves access checks to kufu as the mount has no
+    // default_permissions, which would apply the 0777 root owned attributes of
+    // every other file as well. Only files made private, like those of
+    // secrets, are checked against the owner and its permission bits.
+    pub fn check_access(&self, inode: u64, uid: u32, read: bool, write: bool) -> Result<()> {
+        if self.is_pending(inode) {
+            return Ok(());
+        }
+        let attr = self.store.get_inode_attr(inode)?;
+        if attr.mode & 0o077 != 0 {
+            return Ok(());
+        }
+        let denied = uid != attr.uid
+            || (read && attr.mode & 0o400 == 0)
+            || (write && attr.mode & 0o200 == 0);
+        if denied {
+            return Err(AccessDenied(inode));
+        }
+        Ok(())
+    }
+
+    pub fn open(&mut self, inode: u64, read: bool, write: bool, truncate: bool) -> Result<u64> {
+        let pending = self.is_pending(inode);
+        if !pending {
+            self.pending.remove(&inode);
+        }
+        let fh = if pending {
+            new_file_handle(read, write)
+        } else {
+            self.open_dir(inode, read, write)?
+        };
         if write {
-            fh |= FILE_HANDLE_WRITE_BIT;
+            let data = if truncate || pending {
+                vec![]
+            } else {
+                self.store.get_data(inode)?.to_vec()
+            };
+            self.writes.insert(
+                fh,
+                WriteBuffer {
+                    inode,
+                    data,
+                    dirty: truncate,
+                },
+            );
         }
         Ok(fh)
     }
 
-    pub fn read(&self, inode: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+    pub fn read(&self, inode: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
         info!("read inode: {}, offset :{} size: {}", inode, offset, size);
-        let ivec = self.store.get_data(inode)?;
-        let data = &*ivec;
+        let ivec;
+        let data = match self.writes.get(&fh) {
+            Some(buffer) => &buffer.data[..],
+            None if self.is_pending(inode) => &[],
+            None => {
+                ivec = self.store.get_data(inode)?;
+                &*ivec
+            }
+        };
         let read_size = min(size, data.len().saturating_sub(offset as usize) as u32);
-        let start = offset as usize;
+        let start = min(offset as usize, data.len());
         let end = start + read_size as usize;
         let buffer = &data[start..end];
         Ok(buffer.to_vec())
     }
+
+    pub fn write(&mut self, fh: u64, offset: i64, data: &[u8]) -> Result<u32> {
+        let buffer = match self.writes.get_mut(&fh) {
+            Some(buffer) => buffer,
+            None => return Err(WriteHandleNotFound(fh)),
+        };
+        let start = u64::try_from(offset).map_err(|_| InvalidOffset(offset))?;
+        let end = buffer_size(start + data.len() as u64)?;
+        let start = end - data.len();
+        if buffer.data.len() < end {
+            buffer.data.resize(end, 0);
+        }
+        buffer.data[start..end].copy_from_slice(data);
+        buffer.dirty = true;
+        Ok(data.len() as u32)
+    }
+
+    // Files are only sent to the cluster on flush, so a truncate needs a handle
+    // opened for writing whose flush follows.
+    pub fn truncate(&mut self, inode: u64, fh: Option<u64>, size: u64) -> Result<()> {
+        let size = buffer_size(size)?;
+        let mut truncated = false;
+        for (handle, buffer) in self.writes.iter_mut() {
+            if buffer.inode != inode || fh.is_some_and(|fh| fh != *handle) {
+                continue;
+            }
+            buffer.data.resize(size, 0);
+            buffer.dirty = true;
+            truncated = true;
+        }
+        if !truncated {
+            return Err(TruncateWithoutHandle(inode));
+        }
+        Ok(())
+    }
+
+    // Returns the pending content of a file handle if it has unsaved changes.
+    pub fn dirty_data(&self, fh: u64) -> Option<Vec<u8>> {
+        match self.writes.get(&fh) {
+            Some(buffer) if buffer.dirty => Some(buffer.data.clone()),
+            _ => None,
+        }
+    }
+
+    // Stores the object accepted by the api server and marks the handle clean,
+    // the split view of the object is rendered again as well.
+    pub fn commit(&mut self, fh: u64, meta: &ClusterObjectMeta, obj: &DynamicObject) -> Result<()> {
+        let buffer = match self.writes.get_mut(&fh) {
+            Some(buffer) => buffer,
+            None => return Err(WriteHandleNotFound(fh)),
+        };
+        self.store.update(ClusterObject { meta, obj })?;
+        buffer.dirty = false;
+        Ok(())
+    }
+
+    pub fn mark_clean(&mut self, fh: u64) {
+        if let Some(buffer) = self.writes.get_mut(&fh) {
+            buffer.dirty = false;
+        }
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        let inode = match self.writes.remove(&fh) {
+            Some(buffer) => buffer.inode,
+            None => return,
+        };
+        if !self.writes.values().any(|b| b.inode == inode) {
+            self.pending.remove(&inode);
+        }
+    }
+}
+
+// Every name in the tree comes from an object, which is valid utf-8.
+pub fn utf8_name(name: &OsStr) -> Result<&str> {
+    name.to_str()
+        .ok_or_else(|| NonUtf8Name(name.to_string_lossy().to_string()))
+}
+
+fn buffer_size(size: u64) -> Result<usize> {
+    match usize::try_from(size) {
+        Ok(size) if size <= MAX_FILE_SIZE => Ok(size),
+        _ => Err(FileTooLarge(size, MAX_FILE_SIZE)),
+    }
+}
+
+fn new_file_handle(read: bool, write: bool) -> u64 {
+    let mut fh = next_file_handle();
+    if read {
+        fh |= FILE_HANDLE_READ_BIT;
+    }
+    if write {
+        fh |= FILE_HANDLE_WRITE_BIT;
+    }
+    fh
 }
 
 fn next_file_handle() -> u64 {
@@ -3,44 +3,224 @@ pub mod inner;
 use fuser::consts::FOPEN_DIRECT_IO;
 pub use inner::*;
 
-use fuser::Filesystem;
+#[cfg(target_os = "macos")]
+use fuser::ReplyXTimes;
+use fuser::{FileAttr, Filesystem};
 use fuser::{
     KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus,
     ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite,
-    ReplyXTimes, ReplyXattr, Request, TimeOrNow,
+    ReplyXattr, Request, TimeOrNow,
+};
+use kube::{
+    api::DeleteParams,
+    core::{DynamicObject, GroupVersionKind},
+    discovery::ApiResource,
+    Api,
 };
-use kube::Client;
 use libc::{ENOSYS, EPERM};
 use std::ffi::OsStr;
 use std::os::raw::c_int;
 use std::path::Path;
 use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
 use tracing::*;
 
-use crate::db::SledDb;
-use crate::Result as KufuResult;
-
-pub struct Fs {
-    pub inner: inner::FsInner,
-    pub client: Client,
+use crate::config::Mount;
+use crate::db::utils::get_namespace_key;
+use crate::db::{FSManger, Storage};
+use crate::error::Error::{ClusterNotFound, NamespaceDeletionDisabled, UnsupportedViewPart};
+use crate::kube::{api, ClusterPool};
+use crate::view;
+use crate::{ClusterObject, ClusterObjectMeta, Result as KufuResult};
+
+pub struct Fs<S> {
+    pub inner: inner::FsInner<S>,
+    pub clusters: ClusterPool,
     pub mount_point: String,
+    delete_params: DeleteParams,
+    delete_namespaces: bool,
+    runtime: Handle,
 }
 
-impl Fs {
-    pub fn new(client: Client, store: SledDb, mount_point: String) -> Fs {
+impl<S: Storage + FSManger> Fs<S> {
+    // must be called inside the tokio runtime, api calls made by fuse callbacks
+    // are blocked on through its handle.
+    pub fn new(clusters: ClusterPool, store: S, mount: &Mount) -> Fs<S> {
         Fs {
-            inner: FsInner::new(store),
-            client,
-            mount_point,
+            inner: FsInner::new(store, mount.view.clone()),
+            clusters,
+            mount_point: mount.path.clone(),
+            delete_params: mount.delete_params(),
+            delete_namespaces: mount.delete_namespaces,
+            runtime: Handle::current(),
         }
     }
 
     pub fn init(&self) -> KufuResult<()> {
-        self.inner.init(self.mount_point.clone(), vec![])
+        self.inner.init(self.mount_point.clone())
+    }
+
+    fn resolve(
+        &self,
+        cluster: &str,
+        obj: &DynamicObject,
+    ) -> KufuResult<(ClusterObjectMeta, Api<DynamicObject>)> {
+        let cluster_api = match self.clusters.read().unwrap().get(cluster) {
+            Some(cluster_api) => cluster_api.clone(),
+            None => return Err(ClusterNotFound(cluster.to_string())),
+        };
+        self.runtime
+            .block_on(api::resolve(&cluster_api, cluster, obj))
+    }
+
+    // Sends the unsaved content of the file handle to the api server, the local
+    // data is only replaced after the server accepted the object. Files created
+    // by the user are mounted by the watcher once the object shows up.
+    pub fn sync_to_cluster(&mut self, ino: u64, fh: u64) -> KufuResult<()> {
+        let data = match self.inner.dirty_data(fh) {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        if data.is_empty() && self.inner.is_pending(ino) {
+            return Ok(());
+        }
+        if let Some((source, part)) = self.inner.store.get_part(ino)? {
+            return self.sync_part(ino, fh, &data, source, &part);
+        }
+        let obj = api::parse_object(&data, self.inner.format_of(ino)?)?;
+        let cluster = self.inner.cluster_of(ino)?;
+        let (meta, api) = self.resolve(&cluster, &obj)?;
+        if self.inner.is_pending(ino) {
+            self.inner.check_placement(
+                ino,
+                &ClusterObject {
+                    meta: &meta,
+                    obj: &obj,
+                },
+            )?;
+            self.runtime.block_on(api::create(&api, &obj))?;
+            self.inner.mark_clean(fh);
+            return Ok(());
+        }
+        let mounted =
+            api::parse_object(&self.inner.store.get_data(ino)?, self.inner.format_of(ino)?)?;
+        api::check_identity(&obj, &mounted)?;
+        let obj = self.unredact(&meta, &api, obj)?;
+        let obj = self.runtime.block_on(api::replace(&api, &obj))?;
+        self.inner.commit(fh, &meta, &obj)
+    }
+
+    // Values of redacted secrets are not mounted, they are taken from the live
+    // object before an edit is sent.
+    fn unredact(
+        &self,
+        meta: &ClusterObjectMeta,
+        api: &Api<DynamicObject>,
+        mut obj: DynamicObject,
+    ) -> KufuResult<DynamicObject> {
+        if view::is_redacted(meta) {
+            let live = self.runtime.block_on(api::get(api, &obj))?;
+            view::unredact(&mut obj, &live);
+        }
+        Ok(obj)
+    }
+
+    // Merges an edited part of a split view into the full object, which is sent
+    // to the api server like an edit of the object file.
+    fn sync_part(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        data: &[u8],
+        source: u64,
+        part: &str,
+    ) -> KufuResult<()> {
+        let format = self.inner.view.format();
+        let mut obj = api::parse_object(&self.inner.store.get_data(source)?, format)?;
+        view::merge(&mut obj, part, data, format)?;
+        let cluster = self.inner.cluster_of(ino)?;
+        let (meta, api) = self.resolve(&cluster, &obj)?;
+        let obj = self.unredact(&meta, &api, obj)?;
+        let obj = self.runtime.block_on(api::replace(&api, &obj))?;
+        self.inner.commit(fh, &meta, &obj)
+    }
+
+    // Creates a namespace for a directory made under the namespace root, the
+    // directory is mounted right away while its yaml comes with the watch event.
+    pub fn create_namespace(&mut self, parent: u64, name: &OsStr) -> KufuResult<FileAttr> {
+        let cluster = self.inner.namespace_root(parent)?;
+        let name = utf8_name(name)?;
+        let obj = namespace_object(name);
+        let (_, api) = self.resolve(&cluster, &obj)?;
+        self.runtime.block_on(api::create(&api, &obj))?;
+        let inode = self
+            .inner
+            .store
+            .mount_dir(get_namespace_key(&cluster, name), parent)?;
+        self.inner.get_attr(inode)
+    }
+
+    // An empty directory only means nothing mounted is left in the namespace,
+    // kinds that are not watched may still have objects in it.
+    pub fn delete_namespace(&mut self, parent: u64, name: &OsStr) -> KufuResult<()> {
+        let cluster = self.inner.namespace_root(parent)?;
+        let name = utf8_name(name)?;
+        self.inner.look_up(parent, OsStr::new(name))?;
+        self.check_namespace_deletion(parent, name)?;
+        let obj = namespace_object(name);
+        let (_, api) = self.resolve(&cluster, &obj)?;
+        self.runtime
+            .block_on(api::delete(&api, &obj, &self.delete_params))
+    }
+
+    // A namespace goes with everything in it, so it is only deleted if enabled
+    // and nothing mounted is left in its directory.
+    fn check_namespace_deletion(&self, parent: u64, name: &str) -> KufuResult<()> {
+        if let Ok(dir) = self.inner.look_up(parent, OsStr::new(name)) {
+            self.inner.check_empty_dir(dir.ino)?;
+        }
+        if !self.delete_namespaces {
+            return Err(NamespaceDeletionDisabled(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // Deletes the object behind the file, the entry stays mounted until the
+    // watcher receives the delete event.
+    pub fn delete_from_cluster(&mut self, parent: u64, name: &OsStr) -> KufuResult<()> {
+        let ino = self.inner.look_up(parent, name)?.ino;
+        if self.inner.is_pending(ino) {
+            self.inner.forget(ino);
+            return Ok(());
+        }
+        if self.inner.store.get_part(ino)?.is_some() {
+            return Err(UnsupportedViewPart(name.to_string_lossy().to_string()));
+        }
+        let data = self.inner.store.get_data(ino)?;
+        let obj = api::parse_object(&data, self.inner.format_of(ino)?)?;
+        if is_namespace_object(&obj) {
+            let name = obj.metadata.name.clone().unwrap_or_default();
+            self.check_namespace_deletion(parent, &name)?;
+        }
+        let cluster = self.inner.cluster_of(ino)?;
+        let (_, api) = self.resolve(&cluster, &obj)?;
+        self.runtime
+            .block_on(api::delete(&api, &obj, &self.delete_params))
     }
 }
 
-impl Filesystem for Fs {
+fn namespace_object(name: &str) -> DynamicObject {
+    let gvk = GroupVersionKind::gvk("", "v1", "Namespace");
+    DynamicObject::new(name, &ApiResource::from_gvk(&gvk))
+}
+
+fn is_namespace_object(obj: &DynamicObject) -> bool {
+    obj.types
+        .as_ref()
+        .is_some_and(|t| t.api_version == "v1" && t.kind == "Namespace")
+}
+
+impl<S: Storage + FSManger> Filesystem for Fs<S> {
     fn init(
         &mut self,
         _req: &Request<'_>,
@@ -61,7 +241,7 @@ impl Filesystem for Fs {
                 if !e.to_string().contains("._.") {
                     error!("fail to lookup name: {:?} in parent err: {:?}", name, e);
                 }
-                reply.error(libc::ENOENT)
+                reply.error(e.errno())
             }
         }
     }
@@ -73,7 +253,7 @@ impl Filesystem for Fs {
             Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
             Err(e) => {
                 error!("fail to get attr err: {:?}", e);
-                reply.error(libc::ENOENT)
+                reply.error(e.errno())
             }
         }
     }
@@ -97,11 +277,24 @@ impl Filesystem for Fs {
         reply: ReplyAttr,
     ) {
         debug!(
-            "[Not Implemented] setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
+            "setattr(ino: {:#x?}, mode: {:?}, uid: {:?}, \
             gid: {:?}, size: {:?}, fh: {:?}, flags: {:?})",
             ino, mode, uid, gid, size, fh, flags
         );
-        reply.error(ENOSYS);
+        if let Some(size) = size {
+            if let Err(e) = self.inner.truncate(ino, fh, size) {
+                error!("fail to truncate inode: {}, err: {:?}", ino, e);
+                reply.error(e.errno());
+                return;
+            }
+        }
+        match self.inner.get_attr(ino) {
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
+            Err(e) => {
+                error!("fail to set attr err: {:?}", e);
+                reply.error(e.errno())
+            }
+        }
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
@@ -120,11 +313,21 @@ impl Filesystem for Fs {
         reply: ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] mknod(parent: {:#x?}, name: {:?}, mode: {}, \
+            "mknod(parent: {:#x?}, name: {:?}, mode: {}, \
             umask: {:#x?}, rdev: {})",
             parent, name, mode, umask, rdev
         );
-        reply.error(ENOSYS);
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            reply.error(EPERM);
+            return;
+        }
+        match self.inner.create(parent, name) {
+            Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+            Err(e) => {
+                error!("fail to create file: {:?}, err: {:?}", name, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn mkdir(
@@ -137,26 +340,38 @@ impl Filesystem for Fs {
         reply: ReplyEntry,
     ) {
         debug!(
-            "[Not Implemented] mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
+            "mkdir(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?})",
             parent, name, mode, umask
         );
-        reply.error(ENOSYS);
+        match self.create_namespace(parent, name) {
+            Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+            Err(e) => {
+                error!("fail to create namespace: {:?}, err: {:?}", name, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!(
-            "[Not Implemented] unlink(parent: {:#x?}, name: {:?})",
-            parent, name,
-        );
-        reply.error(ENOSYS);
+        debug!("unlink(parent: {:#x?}, name: {:?})", parent, name);
+        match self.delete_from_cluster(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fail to delete file: {:?}, err: {:?}", name, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!(
-            "[Not Implemented] rmdir(parent: {:#x?}, name: {:?})",
-            parent, name,
-        );
-        reply.error(ENOSYS);
+        debug!("rmdir(parent: {:#x?}, name: {:?})", parent, name);
+        match self.delete_namespace(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fail to delete namespace: {:?}, err: {:?}", name, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn symlink(
@@ -207,7 +422,7 @@ impl Filesystem for Fs {
         reply.error(EPERM);
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("call open inode: {:?}", ino);
         let (_, read, write) = match flags & libc::O_ACCMODE {
             libc::O_RDONLY => {
@@ -226,11 +441,16 @@ impl Filesystem for Fs {
                 return;
             }
         };
-        match self.inner.open_dir(ino, read, write) {
+        let truncate = flags & libc::O_TRUNC != 0;
+        let opened = self
+            .inner
+            .check_access(ino, req.uid(), read, write)
+            .and_then(|()| self.inner.open(ino, read, write, truncate));
+        match opened {
             Ok(fh) => reply.opened(fh, FOPEN_DIRECT_IO),
             Err(e) => {
-                error!("fail to open dir, err: {:?}", e);
-                reply.error(ENOSYS);
+                error!("fail to open file, err: {:?}", e);
+                reply.error(e.errno());
             }
         }
     }
@@ -242,15 +462,15 @@ impl Filesystem for Fs {
         fh: u64,
         offset: i64,
         size: u32,
-        flags: i32,
-        lock_owner: Option<u64>,
-        mut reply: ReplyData,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
     ) {
-        match self.inner.read(ino, offset, size) {
+        match self.inner.read(ino, fh, offset, size) {
             Ok(data) => reply.data(&data),
             Err(e) => {
                 error!("fail to read err: {:?}", e);
-                reply.error(ENOSYS);
+                reply.error(e.errno());
             }
         }
     }
@@ -268,7 +488,7 @@ impl Filesystem for Fs {
         reply: ReplyWrite,
     ) {
         debug!(
-            "[Not Implemented] write(ino: {:#x?}, fh: {}, offset: {}, data.len(): {}, \
+            "write(ino: {:#x?}, fh: {}, offset: {}, data.len(): {}, \
             write_flags: {:#x?}, flags: {:#x?}, lock_owner: {:?})",
             ino,
             fh,
@@ -278,36 +498,55 @@ impl Filesystem for Fs {
             flags,
             lock_owner
         );
-        reply.error(ENOSYS);
+        match self.inner.write(fh, offset, data) {
+            Ok(size) => reply.written(size),
+            Err(e) => {
+                error!("fail to write err: {:?}", e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         debug!(
-            "[Not Implemented] flush(ino: {:#x?}, fh: {}, lock_owner: {:?})",
+            "flush(ino: {:#x?}, fh: {}, lock_owner: {:?})",
             ino, fh, lock_owner
         );
-        reply.error(ENOSYS);
+        match self.sync_to_cluster(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fail to apply inode: {} to cluster, err: {:?}", ino, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn release(
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
+        self.inner.release(fh);
         reply.ok();
     }
 
     fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         debug!(
-            "[Not Implemented] fsync(ino: {:#x?}, fh: {}, datasync: {})",
+            "fsync(ino: {:#x?}, fh: {}, datasync: {})",
             ino, fh, datasync
         );
-        reply.error(ENOSYS);
+        match self.sync_to_cluster(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("fail to apply inode: {} to cluster, err: {:?}", ino, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
@@ -333,7 +572,7 @@ impl Filesystem for Fs {
             Ok(fh) => reply.opened(fh, FOPEN_DIRECT_IO),
             Err(e) => {
                 error!("fail to open dir, err: {:?}", e);
-                reply.error(ENOSYS);
+                reply.error(e.errno());
             }
         }
     }
@@ -342,18 +581,18 @@ impl Filesystem for Fs {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        match self.inner.read_dir(ino, offset, &mut reply) {
+        match self.inner.read_dir(ino, fh, offset, &mut reply) {
             Ok(()) => {
                 info!("success call read_dir, inode: {:?}", ino);
                 reply.ok();
             }
             Err(e) => {
                 error!("fail to read dir, err: {:?}", e);
-                reply.error(ENOSYS);
+                reply.error(e.errno());
             }
         }
     }
@@ -377,10 +616,11 @@ impl Filesystem for Fs {
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: ReplyEmpty,
     ) {
+        self.inner.release_dir(fh);
         reply.ok();
     }
 
@@ -467,11 +707,30 @@ impl Filesystem for Fs {
         reply: ReplyCreate,
     ) {
         debug!(
-            "[Not Implemented] create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
+            "create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
             flags: {:#x?})",
             parent, name, mode, umask, flags
         );
-        reply.error(ENOSYS);
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let created = self
+            .inner
+            .create(parent, name)
+            .and_then(|attr| Ok((attr, self.inner.open(attr.ino, read, write, true)?)));
+        match created {
+            Ok((attr, fh)) => reply.created(&Duration::new(0, 0), &attr, 0, fh, FOPEN_DIRECT_IO),
+            Err(e) => {
+                error!("fail to create file: {:?}, err: {:?}", name, e);
+                reply.error(e.errno());
+            }
+        }
     }
 
     fn getlk(
@@ -603,11 +862,13 @@ impl Filesystem for Fs {
         reply.error(ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn setvolname(&mut self, _req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
         debug!("[Not Implemented] setvolname(name: {:?})", name);
         reply.error(ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn exchange(
         &mut self,
         _req: &Request<'_>,
@@ -626,6 +887,7 @@ impl Filesystem for Fs {
         reply.error(ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn getxtimes(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyXTimes) {
         debug!("[Not Implemented] getxtimes(ino: {:#x?})", ino);
         reply.error(ENOSYS);
@@ -0,0 +1,114 @@
+use super::ClusterApi;
+use crate::config::Format;
+use crate::{error::Error::InvalidClusterObject, ClusterObjectMeta, Result};
+use kube::{
+    api::{DeleteParams, PostParams},
+    core::{DynamicObject, GroupVersionKind},
+    discovery::{self, Scope},
+    Api,
+};
+
+const FIELD_MANAGER: &str = "kufu";
+
+pub fn parse_object(data: &[u8], format: Format) -> Result<DynamicObject> {
+    let obj = format.parse(data)?;
+    if obj.metadata.name.is_none() {
+        return Err(InvalidClusterObject("metadata.name is empty".to_string()));
+    }
+    Ok(obj)
+}
+
+// An edited file has to keep the object it shows, a changed group, kind,
+// namespace or name would replace another object through this file.
+pub fn check_identity(edited: &DynamicObject, mounted: &DynamicObject) -> Result<()> {
+    let identity = |o: &DynamicObject| {
+        let types = o.types.clone().unwrap_or_default();
+        let group = match types.api_version.split_once('/') {
+            Some((group, _)) => group.to_string(),
+            None => String::new(),
+        };
+        (
+            group,
+            types.kind,
+            o.metadata.namespace.clone(),
+            o.metadata.name.clone(),
+        )
+    };
+    if identity(edited) != identity(mounted) {
+        return Err(InvalidClusterObject(
+            "kind, namespace and name of a mounted object can not be changed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Finds the api of the object, watched kinds are served from the api pool and
+// everything else goes through discovery.
+pub async fn resolve(
+    cluster_api: &ClusterApi,
+    cluster: &str,
+    obj: &DynamicObject,
+) -> Result<(ClusterObjectMeta, Api<DynamicObject>)> {
+    let client = &cluster_api.client;
+    let types = match &obj.types {
+        Some(types) => types.clone(),
+        None => {
+            return Err(InvalidClusterObject(
+                "apiVersion and kind are required".to_string(),
+            ))
+        }
+    };
+    let gvk: GroupVersionKind = types.try_into()?;
+    let pooled = cluster_api
+        .pool
+        .read()
+        .unwrap()
+        .get(&gvk)
+        .map(|c| (c.ar.clone(), c.caps.clone()));
+    let (ar, caps) = match pooled {
+        Some(pooled) => pooled,
+        None => discovery::pinned_kind(client, &gvk).await?,
+    };
+    let api = match (&caps.scope, &obj.metadata.namespace) {
+        (Scope::Namespaced, Some(ns)) => Api::namespaced_with(client.clone(), ns, &ar),
+        (Scope::Namespaced, None) => {
+            return Err(InvalidClusterObject(
+                "metadata.namespace is required for namespaced resource".to_string(),
+            ))
+        }
+        (Scope::Cluster, _) => Api::all_with(client.clone(), &ar),
+    };
+    let meta = ClusterObjectMeta::new(cluster.to_string(), &ar, caps, cluster_api.view.clone());
+    Ok((meta, api))
+}
+
+pub async fn get(api: &Api<DynamicObject>, obj: &DynamicObject) -> Result<DynamicObject> {
+    let name = obj.metadata.name.as_ref().unwrap();
+    Ok(api.get(name).await?)
+}
+
+pub async fn replace(api: &Api<DynamicObject>, obj: &DynamicObject) -> Result<DynamicObject> {
+    let name = obj.metadata.name.as_ref().unwrap();
+    Ok(api.replace(name, &post_params(), obj).await?)
+}
+
+pub async fn create(api: &Api<DynamicObject>, obj: &DynamicObject) -> Result<DynamicObject> {
+    Ok(api.create(&post_params(), obj).await?)
+}
+
+pub async fn delete(
+    api: &Api<DynamicObject>,
+    obj: &DynamicObject,
+    dp: &DeleteParams,
+) -> Result<()> {
+    let name = obj.metadata.name.as_ref().unwrap();
+    api.delete(name, dp).await?;
+    Ok(())
+}
+
+fn post_params() -> PostParams {
+    PostParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,193 @@
+use super::{ClusterApi, WatchOptions, Watcher};
+use crate::{
+    config::{self, ClusterConfig, KubeConfig},
+    db::{FSManger, Storage},
+    Result,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+const KUBECONFIG_POLL_PERIOD: Duration = Duration::from_secs(10);
+
+pub type ClusterPool = Arc<RwLock<HashMap<String, ClusterApi>>>;
+
+// Runs a watcher for every mounted cluster. Kubeconfig files are polled, so a
+// cluster directory comes and goes with its context and is remounted when the
+// server or user of the context changes.
+pub struct ClusterManager<S> {
+    kube_configs: Vec<KubeConfig>,
+    default_name: String,
+    opts: WatchOptions,
+    store: S,
+    mount_point: String,
+    clusters: ClusterPool,
+    running: HashMap<String, Running>,
+}
+
+// The watch task of a mounted cluster and the fingerprint of its config.
+struct Running {
+    fingerprint: String,
+    stop: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl<S: Storage + FSManger + Clone + 'static> ClusterManager<S> {
+    pub fn new(
+        kube_configs: Vec<KubeConfig>,
+        default_name: String,
+        opts: WatchOptions,
+        store: S,
+        mount_point: String,
+    ) -> ClusterManager<S> {
+        ClusterManager {
+            kube_configs,
+            default_name,
+            opts,
+            store,
+            mount_point,
+            clusters: Arc::new(RwLock::new(HashMap::new())),
+            running: HashMap::new(),
+        }
+    }
+
+    pub fn clusters(&self) -> ClusterPool {
+        Arc::clone(&self.clusters)
+    }
+
+    // Mounts the configured clusters, the mount point has to be mounted already.
+    // Clusters left over from the last run are dropped.
+    pub async fn start(&mut self) -> Result<()> {
+        let clusters = config::clusters(&self.kube_configs, &self.default_name)?;
+        let root = self.store.get_inode(self.mount_point.clone())?;
+        let names = self
+            .store
+            .entries(root)
+            .map(|entry| entry.map(|(name, _)| name))
+            .collect::<Result<Vec<_>>>()?;
+        for name in names {
+            if !clusters.iter().any(|c| c.name == name) {
+                self.store.unmount_cluster(&name)?;
+            }
+        }
+        self.sync(clusters).await
+    }
+
+    pub async fn run(mut self) {
+        let mut mtimes = self.kubeconfig_mtimes();
+        let mut interval = tokio::time::interval(KUBECONFIG_POLL_PERIOD);
+        loop {
+            interval.tick().await;
+            let current = self.kubeconfig_mtimes();
+            if current == mtimes {
+                continue;
+            }
+            mtimes = current;
+            info!("kubeconfig changed, reload clusters");
+            let result = match config::clusters(&self.kube_configs, &self.default_name) {
+                Ok(clusters) => self.sync(clusters).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("reload clusters failed, err: {:?}", e);
+            }
+        }
+    }
+
+    async fn sync(&mut self, clusters: Vec<ClusterConfig>) -> Result<()> {
+        let stale: Vec<String> = self
+            .running
+            .keys()
+            .filter(|name| !clusters.iter().any(|c| c.name == **name))
+            .cloned()
+            .collect();
+        for name in stale {
+            self.stop(&name).await?;
+        }
+        for c in clusters {
+            let fingerprint = c.fingerprint()?;
+            match self.running.get(&c.name) {
+                Some(running) if running.fingerprint == fingerprint => continue,
+                Some(_) => {
+                    info!("config of cluster {} changed, remount it", c.name);
+                    self.stop(&c.name).await?;
+                }
+                None => {}
+            }
+            if let Err(e) = self.launch(&c, fingerprint).await {
+                error!("mount cluster {} failed, err: {:?}", c.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn launch(&mut self, c: &ClusterConfig, fingerprint: String) -> Result<()> {
+        let mut watcher = Watcher::new(
+            c.name.clone(),
+            c,
+            Box::new(self.store.clone()),
+            self.opts.clone(),
+        )
+        .await?;
+        let root = self.store.get_inode(self.mount_point.clone())?;
+        self.store.mount_dir(&c.name, root)?;
+        self.clusters
+            .write()
+            .unwrap()
+            .insert(c.name.clone(), watcher.cluster_api());
+
+        let name = c.name.clone();
+        let stop = watcher.stop_signal();
+        let stopped = Arc::clone(&stop);
+        let task = tokio::spawn(async move {
+            // nothing is watched before the api pool is built, so it can be
+            // dropped right away, watch stops its streams itself
+            let built = tokio::select! {
+                built = watcher.build_api_pool() => built,
+                _ = stopped.notified() => return,
+            };
+            let result = match built {
+                Ok(()) => watcher.watch().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("watch cluster {} failed, err: {:?}", name, e);
+            }
+        });
+        info!("mount cluster {}", c.name);
+        self.running.insert(
+            c.name.clone(),
+            Running {
+                fingerprint,
+                stop,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    async fn stop(&mut self, name: &str) -> Result<()> {
+        // the stream tasks are gone once the watch task ends, none of them
+        // writes to the cluster directory after it is unmounted
+        if let Some(running) = self.running.remove(name) {
+            running.stop.notify_one();
+            let _ = running.task.await;
+        }
+        self.clusters.write().unwrap().remove(name);
+        self.store.unmount_cluster(name)?;
+        info!("unmount cluster {}", name);
+        Ok(())
+    }
+
+    fn kubeconfig_mtimes(&self) -> Vec<Option<SystemTime>> {
+        self.kube_configs
+            .iter()
+            .filter_map(|c| c.watched_path())
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
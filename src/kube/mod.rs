@@ -1,2 +1,7 @@
+pub mod api;
+pub mod cluster;
+pub mod progress;
+pub mod stream;
 pub mod watcher;
+pub use cluster::*;
 pub use watcher::*;
@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Follows which events of a watch stream are processed, so the version a
+// restart resumes from never skips an event that still waits for a retry.
+pub struct Progress {
+    seq: u64,
+    // version of the last event queued
+    latest: Option<String>,
+    // events not processed yet and the version before each of them
+    pending: BTreeMap<u64, Option<String>>,
+    keys: HashMap<String, BTreeSet<u64>>,
+    saved: Option<String>,
+}
+
+impl Progress {
+    pub fn new(rv: Option<String>) -> Progress {
+        Progress {
+            seq: 0,
+            latest: rv.clone(),
+            pending: BTreeMap::new(),
+            keys: HashMap::new(),
+            saved: rv,
+        }
+    }
+
+    // Numbers the queued event. Events without a version, like all but the
+    // last event of a relist, do not move the version.
+    pub fn queued(&mut self, key: &str, rv: Option<String>) -> u64 {
+        self.seq += 1;
+        self.pending.insert(self.seq, self.latest.clone());
+        self.keys
+            .entry(key.to_string())
+            .or_default()
+            .insert(self.seq);
+        if rv.is_some() {
+            self.latest = rv;
+        }
+        self.seq
+    }
+
+    // Moves the version past events which queued nothing, like a relist that
+    // changed nothing. Returns the version to save if nothing is pending.
+    pub fn seen(&mut self, rv: String) -> Option<String> {
+        self.latest = Some(rv);
+        if !self.pending.is_empty() || self.latest == self.saved {
+            return None;
+        }
+        self.saved = self.latest.clone();
+        self.saved.clone()
+    }
+
+    // Marks the event processed together with the older events of its key,
+    // which the queue replaced by it. Returns the version to save if it moved.
+    pub fn processed(&mut self, key: &str, seq: u64) -> Option<String> {
+        if let Some(seqs) = self.keys.get_mut(key) {
+            let newer = seqs.split_off(&(seq + 1));
+            for done in std::mem::replace(seqs, newer) {
+                self.pending.remove(&done);
+            }
+            if seqs.is_empty() {
+                self.keys.remove(key);
+            }
+        }
+        let resume = match self.pending.values().next() {
+            Some(rv) => rv.clone(),
+            None => self.latest.clone(),
+        };
+        if resume.is_none() || resume == self.saved {
+            return None;
+        }
+        self.saved = resume.clone();
+        resume
+    }
+}
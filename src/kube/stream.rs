@@ -0,0 +1,99 @@
+use futures::{stream, Stream, StreamExt};
+use kube::{
+    api::{ListParams, WatchEvent},
+    core::DynamicObject,
+    runtime::watcher::{Error, Event},
+    Api,
+};
+
+type WatchStream = stream::BoxStream<'static, kube::Result<WatchEvent<DynamicObject>>>;
+
+enum State {
+    // nothing is known, the kind has to be listed first
+    Empty,
+    InitWatch { rv: String },
+    Watching { rv: String, stream: WatchStream },
+}
+
+// Like kube's watcher, but the watch starts from the given resource version
+// instead of a full list. Every event comes with the resource version to
+// resume from, a version the api server no longer has is answered with a relist.
+pub fn watch_from(
+    api: Api<DynamicObject>,
+    lp: ListParams,
+    rv: Option<String>,
+) -> impl Stream<Item = Result<(Event<DynamicObject>, String), Error>> + Send {
+    let state = match rv {
+        Some(rv) => State::InitWatch { rv },
+        None => State::Empty,
+    };
+    stream::unfold((api, lp, state), |(api, lp, state)| async move {
+        let (item, state) = step(&api, &lp, state).await;
+        Some((item, (api, lp, state)))
+    })
+}
+
+async fn step(
+    api: &Api<DynamicObject>,
+    lp: &ListParams,
+    mut state: State,
+) -> (Result<(Event<DynamicObject>, String), Error>, State) {
+    loop {
+        state = match state {
+            State::Empty => {
+                return match api.list(lp).await {
+                    Ok(list) => {
+                        let rv = list.metadata.resource_version.unwrap_or_default();
+                        (
+                            Ok((Event::Restarted(list.items), rv.clone())),
+                            State::InitWatch { rv },
+                        )
+                    }
+                    Err(e) => (Err(Error::InitialListFailed(e)), State::Empty),
+                }
+            }
+            State::InitWatch { rv } => match api.watch(lp, &rv).await {
+                Ok(stream) => State::Watching {
+                    rv,
+                    stream: stream.boxed(),
+                },
+                Err(kube::Error::Api(resp)) if resp.code == 410 => {
+                    return (
+                        Err(Error::WatchStartFailed(kube::Error::Api(resp))),
+                        State::Empty,
+                    )
+                }
+                Err(e) => return (Err(Error::WatchStartFailed(e)), State::InitWatch { rv }),
+            },
+            State::Watching { rv, mut stream } => match stream.next().await {
+                Some(Ok(WatchEvent::Added(o) | WatchEvent::Modified(o))) => {
+                    let rv = o.metadata.resource_version.clone().unwrap_or(rv);
+                    return (
+                        Ok((Event::Applied(o), rv.clone())),
+                        State::Watching { rv, stream },
+                    );
+                }
+                Some(Ok(WatchEvent::Deleted(o))) => {
+                    let rv = o.metadata.resource_version.clone().unwrap_or(rv);
+                    return (
+                        Ok((Event::Deleted(o), rv.clone())),
+                        State::Watching { rv, stream },
+                    );
+                }
+                Some(Ok(WatchEvent::Bookmark(b))) => State::Watching {
+                    rv: b.metadata.resource_version,
+                    stream,
+                },
+                // the version is too old, relist and prune what was missed
+                Some(Ok(WatchEvent::Error(e))) if e.code == 410 => {
+                    return (Err(Error::WatchError(e)), State::Empty)
+                }
+                Some(Ok(WatchEvent::Error(e))) => {
+                    return (Err(Error::WatchError(e)), State::InitWatch { rv })
+                }
+                Some(Err(e)) => return (Err(Error::WatchFailed(e)), State::InitWatch { rv }),
+                None => State::InitWatch { rv },
+            },
+        }
+    }
+}
@@ -1,54 +1,128 @@
+use super::progress::Progress;
+use super::stream::watch_from;
 use crate::{
-    config::KubeConfig, db::Storage, error::Error, ClusterObjectMeta, EventHandlerFactory, Result,
-    SCHEMA,
+    config::{ClusterConfig, Discovery, Layout, Resource, View},
+    controller::{prune, queue::WorkQueue, DynamicControllerFactory},
+    db::{utils::get_resource_index_prefix, Storage},
+    error::Error,
+    ClusterObjectMeta, EventHandlerFactory, ObjectEvent, Result, SCHEMA,
 };
-use futures::{StreamExt, TryStreamExt};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
 use kube::{
     api::ListParams,
-    config::{KubeConfigOptions, Kubeconfig},
-    core::{DynamicObject, GroupVersionKind, TypeMeta},
-    discovery::{self, ApiCapabilities},
-    runtime::watcher,
+    config::KubeConfigOptions,
+    core::{DynamicObject, GroupVersionKind, ObjectMeta},
+    discovery::{self, verbs, ApiCapabilities, ApiResource, Scope},
+    runtime::{watcher, WatchStreamExt},
     Api, Client, Config,
 };
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
 
-struct ApiConfig {
-    caps: ApiCapabilities,
-    api: Api<DynamicObject>,
-    gvk: GroupVersionKind,
+const DEFAULT_DISCOVERY_PERIOD: u64 = 60;
+const DEFAULT_NAMESPACE_PERIOD: u64 = 60;
+
+pub type ApiPool = Arc<RwLock<HashMap<GroupVersionKind, ApiConfig>>>;
+
+#[derive(Clone)]
+pub struct ApiConfig {
+    pub caps: ApiCapabilities,
+    pub api: Api<DynamicObject>,
+    pub gvk: GroupVersionKind,
+    pub ar: ApiResource,
+    pub lp: ListParams,
+    // namespaces a namespaced kind is watched in, all of them if unset
+    pub namespaces: Option<Vec<String>>,
+}
+
+// The client and api pool of one mounted cluster, shared with the file system
+// for write operations.
+#[derive(Clone)]
+pub struct ClusterApi {
+    pub client: Client,
+    pub pool: ApiPool,
+    pub view: View,
+}
+
+// What every cluster watches and how it is laid out.
+#[derive(Clone, Default)]
+pub struct WatchOptions {
+    pub resources: Vec<Resource>,
+    pub resync_period: Option<Duration>,
+    pub discovery: Option<Discovery>,
+    pub view: View,
 }
 
 pub struct Watcher {
-    r: Vec<TypeMeta>,
+    pub cluster: String,
+    r: Vec<Resource>,
     pub client: Client,
-    watch_pool: HashMap<GroupVersionKind, ApiConfig>,
+    watch_pool: ApiPool,
     store: Arc<Box<dyn Storage>>,
+    resync_period: Option<Duration>,
+    discovery: Option<Discovery>,
+    view: View,
+    // namespaces watched one by one when a kind can not be listed cluster wide,
+    // the listable ones are used if unset and listed again with every resync
+    namespaces: Option<Vec<String>>,
+    listed: RwLock<Option<Vec<String>>>,
+    default_namespace: String,
+    stop: Arc<Notify>,
 }
 
 impl Watcher {
-    pub async fn new(r: Vec<TypeMeta>, c: &KubeConfig, store: Box<dyn Storage>) -> Result<Watcher> {
-        let kubeconfig = match (&c.config_path, &c.raw) {
-            (_, Some(data)) => data.to_owned(),
-            (Some(path), None) => Kubeconfig::read_from(path)?,
-            (None, None) => {
-                panic!("kubeconfig information is empty, please set config-path or raw kubeconfig data")
-            }
+    pub async fn new(
+        cluster: String,
+        c: &ClusterConfig,
+        store: Box<dyn Storage>,
+        opts: WatchOptions,
+    ) -> Result<Watcher> {
+        let options = KubeConfigOptions {
+            context: c.context.clone(),
+            ..Default::default()
         };
-        let rest_config =
-            Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?;
+        let rest_config = Config::from_custom_kubeconfig(c.kubeconfig.clone(), &options).await?;
+        let default_namespace = rest_config.default_namespace.clone();
         let client = Client::try_from(rest_config)?;
-        let pool_cap = r.len();
         Ok(Watcher {
-            r,
+            cluster,
             client,
-            watch_pool: HashMap::with_capacity(pool_cap),
+            watch_pool: Arc::new(RwLock::new(HashMap::with_capacity(opts.resources.len()))),
+            r: opts.resources,
             store: Arc::new(store),
+            resync_period: opts.resync_period,
+            discovery: opts.discovery,
+            view: opts.view,
+            namespaces: c.namespaces.clone(),
+            listed: RwLock::new(None),
+            default_namespace,
+            stop: Arc::new(Notify::new()),
         })
     }
 
+    // Notified to end watch, which returns once every stream task is gone.
+    pub fn stop_signal(&self) -> Arc<Notify> {
+        Arc::clone(&self.stop)
+    }
+
+    pub fn api_pool(&self) -> ApiPool {
+        Arc::clone(&self.watch_pool)
+    }
+
+    pub fn cluster_api(&self) -> ClusterApi {
+        ClusterApi {
+            client: self.client.clone(),
+            pool: self.api_pool(),
+            view: self.view.clone(),
+        }
+    }
+
     pub async fn build_api_pool(&mut self) -> Result<()> {
         let tasks: Vec<JoinHandle<Result<ApiConfig>>> = self
             .r
@@ -56,61 +130,712 @@ impl Watcher {
             .map(|r| r.to_owned())
             .map(|r| {
                 let client = self.client.clone();
-                tokio::spawn(async {
-                    let gvk: GroupVersionKind = r.try_into()?;
+                tokio::spawn(async move {
+                    let gvk: GroupVersionKind = r.types.clone().try_into()?;
                     let (ar, caps) = discovery::pinned_kind(&client.clone(), &gvk).await?;
                     let api = Api::<DynamicObject>::all_with(client, &ar);
-                    Ok::<ApiConfig, Error>(ApiConfig { caps, api, gvk })
+                    Ok::<ApiConfig, Error>(ApiConfig {
+                        caps,
+                        api,
+                        gvk,
+                        ar,
+                        lp: r.list_params(),
+                        namespaces: r.namespaces,
+                    })
                 })
             })
             .collect();
         for task in tasks {
-            let api_config = task.await??;
-            self.watch_pool.insert(api_config.gvk.clone(), api_config);
+            // one kind the user may not access must not stop the others
+            let api_config = match task.await? {
+                Ok(api_config) => api_config,
+                Err(e) => {
+                    error!("resolve resource failed, skip it, err: {:?}", e);
+                    continue;
+                }
+            };
+            let mut pool = self.watch_pool.write().unwrap();
+            if !self.collides(&pool, &api_config) {
+                pool.insert(api_config.gvk.clone(), api_config);
+            }
+        }
+        if let Some(d) = &self.discovery {
+            let discovered = self.discover(d).await?;
+            let mut pool = self.watch_pool.write().unwrap();
+            for (gvk, api_config) in by_group(discovered) {
+                if !pool.contains_key(&gvk) && !self.collides(&pool, &api_config) {
+                    pool.insert(gvk, api_config);
+                }
+            }
         }
         Ok(())
     }
 
+    // With the kind layout, kinds of the same name and scope from different
+    // groups would share a directory and replace each other's objects. Only
+    // the first of them is watched.
+    fn collides(
+        &self,
+        pool: &HashMap<GroupVersionKind, ApiConfig>,
+        api_config: &ApiConfig,
+    ) -> bool {
+        if self.view.layout != Layout::Kind {
+            return false;
+        }
+        let kind = api_config.gvk.kind.to_ascii_lowercase();
+        let taken = pool.values().find(|c| {
+            c.gvk != api_config.gvk
+                && c.gvk.kind.to_ascii_lowercase() == kind
+                && c.caps.scope == api_config.caps.scope
+        });
+        match taken {
+            Some(c) => {
+                warn!(
+                    "{}/{} shares its directory with {}/{} in the kind layout, skip it",
+                    api_config.ar.api_version, api_config.gvk.kind, c.ar.api_version, c.gvk.kind
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Finds every kind of the allowed api groups that can be listed and watched.
+    async fn discover(&self, d: &Discovery) -> Result<HashMap<GroupVersionKind, ApiConfig>> {
+        let denied = d.denied_groups();
+        let discovery = match d.allowed_groups() {
+            Some(allowed) => discovery::Discovery::new(self.client.clone())
+                .filter(&allowed.iter().map(String::as_str).collect::<Vec<_>>()),
+            None => discovery::Discovery::new(self.client.clone())
+                .exclude(&denied.iter().map(String::as_str).collect::<Vec<_>>()),
+        }
+        .run()
+        .await?;
+        let mut discovered = HashMap::new();
+        for group in discovery.groups() {
+            if denied.iter().any(|g| g == group.name()) {
+                continue;
+            }
+            for (ar, caps) in group.recommended_resources() {
+                if !caps.supports_operation(verbs::LIST) || !caps.supports_operation(verbs::WATCH) {
+                    continue;
+                }
+                let gvk = GroupVersionKind::gvk(&ar.group, &ar.version, &ar.kind);
+                let api = Api::<DynamicObject>::all_with(self.client.clone(), &ar);
+                discovered.insert(
+                    gvk.clone(),
+                    ApiConfig {
+                        caps,
+                        api,
+                        gvk,
+                        ar,
+                        lp: ListParams::default(),
+                        namespaces: None,
+                    },
+                );
+            }
+        }
+        Ok(discovered)
+    }
+
+    // Kinds listed in the resources config, they stay watched whatever
+    // discovery finds.
+    fn pinned(&self) -> Vec<GroupVersionKind> {
+        self.r
+            .iter()
+            .filter_map(|r| r.types.clone().try_into().ok())
+            .collect()
+    }
+
     pub async fn watch(&self) -> Result<()> {
-        if SCHEMA.lock().unwrap().len() != self.r.len() {
-            panic!(
-                "please make sure all resource EventHandler were registered,
-            kufu decide to watch {} kind k8s reousrce, but only have {} handler
-            ",
-                self.r.len(),
-                SCHEMA.lock().unwrap().len()
-            )
-        }
-        let mut watchers = Vec::with_capacity(self.r.len());
-        for (gvk, api_config) in self.watch_pool.iter() {
-            let mut events = watcher(api_config.api.clone(), ListParams::default()).boxed();
-            let factory = self.dispatcher(gvk).await;
-            let object_meta = ClusterObjectMeta::new(
-                "default".to_string(),
-                gvk.to_owned(),
-                api_config.caps.clone(),
-            );
-            let client = self.client.clone();
-            let s = Arc::clone(&self.store);
-
-            // use queue for raise concurrency https://docs.rs/kube/latest/kube/runtime/utils/struct.StreamBackoff.html
-            watchers.push(tokio::spawn(async move {
-                let handler = factory.build(object_meta, client, s);
-                while let Some(e) = events.try_next().await? {
-                    handler.process(e).await?;
+        let watch_pool = self.watch_pool.read().unwrap().clone();
+        if let Err(e) = self.restore(&watch_pool) {
+            error!("restore cluster {} failed, err: {:?}", self.cluster, e);
+        }
+        let mut kinds = HashMap::with_capacity(watch_pool.len());
+        for (gvk, api_config) in watch_pool.iter() {
+            kinds.insert(gvk.clone(), self.watch_kind(gvk, api_config).await);
+        }
+
+        // kinds watched namespace by namespace follow the namespaces with every
+        // resync, discovery follows crds installed or removed while mounted
+        let period = self
+            .resync_period
+            .unwrap_or(Duration::from_secs(DEFAULT_NAMESPACE_PERIOD));
+        let mut namespaces = tokio::time::interval_at(Instant::now() + period, period);
+        let mut discovery = self.discovery.as_ref().map(|d| {
+            let period = Duration::from_secs(d.period.unwrap_or(DEFAULT_DISCOVERY_PERIOD));
+            (d, tokio::time::interval_at(Instant::now() + period, period))
+        });
+        loop {
+            match &mut discovery {
+                Some((d, interval)) => tokio::select! {
+                    _ = interval.tick() => self.follow_discovery(d, &mut kinds).await,
+                    _ = namespaces.tick() => self.follow_namespaces(&mut kinds).await,
+                    _ = self.stop.notified() => break,
+                },
+                None => tokio::select! {
+                    _ = namespaces.tick() => self.follow_namespaces(&mut kinds).await,
+                    _ = self.stop.notified() => break,
+                },
+            }
+        }
+        for (_, kind) in kinds {
+            kind.stop().await;
+        }
+        info!("stop watching cluster {}", self.cluster);
+        Ok(())
+    }
+
+    async fn follow_discovery(
+        &self,
+        d: &Discovery,
+        kinds: &mut HashMap<GroupVersionKind, KindWatch>,
+    ) {
+        let discovered = match self.discover(d).await {
+            Ok(discovered) => discovered,
+            Err(e) => {
+                error!("discover resources failed, err: {:?}", e);
+                return;
+            }
+        };
+        let pinned = self.pinned();
+        let removed: Vec<GroupVersionKind> = kinds
+            .keys()
+            .filter(|gvk| !discovered.contains_key(gvk) && !pinned.contains(gvk))
+            .cloned()
+            .collect();
+        for gvk in removed {
+            if let Some(kind) = kinds.remove(&gvk) {
+                kind.stop().await;
+            }
+            let api_config = self.watch_pool.write().unwrap().remove(&gvk);
+            if let Some(api_config) = api_config {
+                info!("stop watching {}/{}", api_config.ar.api_version, gvk.kind);
+                // its versions go with the objects, a restart would take them
+                // for a leftover of another config and list the cluster again
+                let meta = self.object_meta(&api_config);
+                let unmounted = prune(&**self.store, &meta, None, &[]).and_then(|()| {
+                    self.store
+                        .remove_watch_state(&get_resource_index_prefix(&meta))
+                });
+                if let Err(e) = unmounted {
+                    error!("unmount {} failed, err: {:?}", meta.gvk.kind, e);
+                }
+            }
+        }
+        for (gvk, api_config) in by_group(discovered) {
+            if kinds.contains_key(&gvk) {
+                continue;
+            }
+            if self.collides(&self.watch_pool.read().unwrap(), &api_config) {
+                continue;
+            }
+            info!("start watching {}/{}", api_config.ar.api_version, gvk.kind);
+            kinds.insert(gvk.clone(), self.watch_kind(&gvk, &api_config).await);
+            self.watch_pool.write().unwrap().insert(gvk, api_config);
+        }
+    }
+
+    // Kinds watched in the listable namespaces start watching namespaces
+    // created while mounted and drop the objects of deleted ones.
+    async fn follow_namespaces(&self, kinds: &mut HashMap<GroupVersionKind, KindWatch>) {
+        if self.namespaces.is_some() || !kinds.values().any(|k| k.follows) {
+            return;
+        }
+        let namespaces = match self.list_namespaces().await {
+            Ok(namespaces) => namespaces,
+            Err(e) => {
+                warn!(
+                    "list namespaces failed, keep the watched ones, err: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        *self.listed.write().unwrap() = Some(namespaces.clone());
+        let watch_pool = self.watch_pool.read().unwrap().clone();
+        for (gvk, kind) in kinds.iter_mut().filter(|(_, k)| k.follows) {
+            let api_config = match watch_pool.get(gvk) {
+                Some(api_config) => api_config,
+                None => continue,
+            };
+            let gone: Vec<String> = kind
+                .streams
+                .keys()
+                .flatten()
+                .filter(|ns| !namespaces.contains(ns))
+                .cloned()
+                .collect();
+            for ns in gone {
+                info!("namespace {} is gone, stop watching {} in it", ns, gvk.kind);
+                if let Some(streams) = kind.streams.remove(&Some(ns.clone())) {
+                    streams.stop().await;
+                }
+                let meta = self.object_meta(api_config);
+                let prefix = format!("{}{}?", get_resource_index_prefix(&meta), ns);
+                let unmounted = prune(&**self.store, &meta, Some(&ns), &[])
+                    .and_then(|()| self.store.remove_watch_state(&prefix));
+                if let Err(e) = unmounted {
+                    error!(
+                        "unmount {} of namespace {} failed, err: {:?}",
+                        gvk.kind, ns, e
+                    );
+                }
+            }
+            for ns in &namespaces {
+                let namespace = Some(ns.clone());
+                if kind.streams.contains_key(&namespace) {
+                    continue;
                 }
-                Ok::<(), Error>(())
-            }));
+                info!("watch {} in new namespace {}", gvk.kind, ns);
+                let streams = self
+                    .watch_namespace(gvk, api_config, namespace.clone())
+                    .await;
+                kind.streams.insert(namespace, streams);
+            }
         }
+    }
 
-        #[allow(unused_must_use)]
-        for w in watchers {
-            w.await?;
+    // Watches resume from the versions of the last run, which only works if the
+    // cluster is watched the same way. Otherwise its tree is dropped and every
+    // kind listed again.
+    fn restore(&self, watch_pool: &HashMap<GroupVersionKind, ApiConfig>) -> Result<()> {
+        let fingerprint = serde_yaml::to_string(&(
+            &self.r,
+            &self.discovery,
+            &self.view,
+            self.namespaces.as_ref(),
+        ))?;
+        let prefixes: Vec<String> = watch_pool
+            .values()
+            .map(|c| get_resource_index_prefix(&self.object_meta(c)))
+            .collect();
+        let stale = self
+            .store
+            .watch_state_keys(&format!("{}/", self.cluster))?
+            .iter()
+            .any(|key| !prefixes.iter().any(|p| key.starts_with(p)));
+        if stale || self.store.get_watch_state(&self.cluster)? != Some(fingerprint.clone()) {
+            info!("cluster {} can not be resumed, list it again", self.cluster);
+            self.store.reset_cluster(&self.cluster)?;
+            self.store.set_watch_state(&self.cluster, &fingerprint)?;
         }
         Ok(())
     }
 
+    fn object_meta(&self, api_config: &ApiConfig) -> ClusterObjectMeta {
+        ClusterObjectMeta::new(
+            self.cluster.clone(),
+            &api_config.ar,
+            api_config.caps.clone(),
+            self.view.clone(),
+        )
+    }
+
+    // Watches a namespaced kind in each of its namespaces if they are listed,
+    // otherwise cluster wide.
+    async fn watch_kind(&self, gvk: &GroupVersionKind, api_config: &ApiConfig) -> KindWatch {
+        let (namespaces, follows) = match (&api_config.caps.scope, &api_config.namespaces) {
+            (Scope::Namespaced, Some(namespaces)) => {
+                (namespaces.iter().cloned().map(Some).collect(), false)
+            }
+            (Scope::Namespaced, None) if !self.can_list_all(api_config).await => {
+                let namespaces = self.allowed_namespaces().await;
+                info!(
+                    "can not list {} cluster wide, watch namespaces {:?} instead",
+                    gvk.kind, namespaces
+                );
+                (namespaces.into_iter().map(Some).collect(), true)
+            }
+            _ => (vec![None], false),
+        };
+        let mut streams = HashMap::with_capacity(namespaces.len());
+        for namespace in namespaces {
+            let watchers = self
+                .watch_namespace(gvk, api_config, namespace.clone())
+                .await;
+            streams.insert(namespace, watchers);
+        }
+        KindWatch { streams, follows }
+    }
+
+    async fn watch_namespace(
+        &self,
+        gvk: &GroupVersionKind,
+        api_config: &ApiConfig,
+        namespace: Option<String>,
+    ) -> AbortOnDrop {
+        let api = match &namespace {
+            Some(ns) => Api::namespaced_with(self.client.clone(), ns, &api_config.ar),
+            None => api_config.api.clone(),
+        };
+        AbortOnDrop(self.watch_stream(gvk, api_config, api, namespace).await)
+    }
+
+    async fn can_list_all(&self, api_config: &ApiConfig) -> bool {
+        let lp = ListParams {
+            limit: Some(1),
+            ..api_config.lp.clone()
+        };
+        match api_config.api.list(&lp).await {
+            Err(kube::Error::Api(resp)) => resp.code != 403,
+            _ => true,
+        }
+    }
+
+    // The configured namespaces, otherwise the listable ones, otherwise the
+    // namespace of the kubeconfig context.
+    async fn allowed_namespaces(&self) -> Vec<String> {
+        if let Some(namespaces) = &self.namespaces {
+            return namespaces.clone();
+        }
+        let listed = self.listed.read().unwrap().clone();
+        if let Some(namespaces) = listed {
+            return namespaces;
+        }
+        match self.list_namespaces().await {
+            Ok(namespaces) => {
+                *self.listed.write().unwrap() = Some(namespaces.clone());
+                namespaces
+            }
+            Err(e) => {
+                warn!(
+                    "list namespaces failed, only watch namespace {}, err: {:?}",
+                    self.default_namespace, e
+                );
+                vec![self.default_namespace.clone()]
+            }
+        }
+    }
+
+    async fn list_namespaces(&self) -> kube::Result<Vec<String>> {
+        let api: Api<Namespace> = Api::all(self.client.clone());
+        let list = api.list(&ListParams::default()).await?;
+        Ok(list
+            .items
+            .into_iter()
+            .filter_map(|ns| ns.metadata.name)
+            .collect())
+    }
+
+    // Spawns the tasks feeding and draining the work queue of one watch stream.
+    async fn watch_stream(
+        &self,
+        gvk: &GroupVersionKind,
+        api_config: &ApiConfig,
+        api: Api<DynamicObject>,
+        namespace: Option<String>,
+    ) -> Vec<JoinHandle<Result<()>>> {
+        let mut watchers = Vec::with_capacity(3);
+        let factory = self.dispatcher(gvk).await;
+        let object_meta = self.object_meta(api_config);
+        let client = self.client.clone();
+        let s = Arc::clone(&self.store);
+        let queue = WorkQueue::new();
+
+        // the version is only saved once every event up to it was processed, so
+        // a restart replays the events still waiting for a retry
+        let version_key = stream_key(&object_meta, namespace.as_deref(), &api_config.lp);
+        let rv = match s.get_watch_state(&version_key) {
+            Ok(rv) => rv,
+            Err(e) => {
+                error!("read resource version failed, list again, err: {:?}", e);
+                None
+            }
+        };
+        let progress = Arc::new(Mutex::new(Progress::new(rv.clone())));
+        let mut known = match s.list(&object_meta) {
+            Ok(objs) => Known::new(objs, namespace.as_deref()),
+            Err(e) => {
+                error!("list mounted {} failed, err: {:?}", gvk.kind, e);
+                Known::new(vec![], None)
+            }
+        };
+        let lp = api_config.lp.clone();
+        let mut events = watch_from(api.clone(), lp.clone(), rv)
+            .backoff(watcher::default_backoff())
+            .boxed();
+        // the resync lists the kind again by restarting the stream, so the list
+        // is queued in order with the watch events
+        let mut resync = self
+            .resync_period
+            .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+
+        // the stream only feeds the queue, so a failing object is retried
+        // without holding back the rest of the kind
+        let producer = Arc::clone(&queue);
+        let queued = Arc::clone(&progress);
+        let versions = Arc::clone(&self.store);
+        let saved_key = version_key.clone();
+        let kind = gvk.kind.clone();
+        let ns = namespace.clone();
+        watchers.push(tokio::spawn(async move {
+            let mut forbidden = false;
+            loop {
+                let e = match &mut resync {
+                    Some(interval) => tokio::select! {
+                        e = events.next() => e,
+                        _ = interval.tick() => {
+                            events = watch_from(api.clone(), lp.clone(), None)
+                                .backoff(watcher::default_backoff())
+                                .boxed();
+                            continue;
+                        }
+                    },
+                    None => events.next().await,
+                };
+                let e = match e {
+                    Some(e) => e,
+                    None => break,
+                };
+                match e {
+                    Ok((e, rv)) => {
+                        forbidden = false;
+                        let events = known.expand(e, &rv);
+                        let mut progress = queued.lock().unwrap();
+                        // the version only moves with the last event of a relist
+                        let last = events.len();
+                        for (i, (key, e)) in events.into_iter().enumerate() {
+                            let rv = (i + 1 == last).then(|| rv.clone());
+                            let seq = progress.queued(&key, rv);
+                            producer.add(key, (seq, e));
+                        }
+                        if last == 0 {
+                            if let Some(rv) = progress.seen(rv) {
+                                save_version(&**versions, &saved_key, &rv);
+                            }
+                        }
+                    }
+                    // the backoff keeps retrying, so access granted later is picked up
+                    Err(e) if is_forbidden(&e) => {
+                        if !forbidden {
+                            warn!(
+                                "forbidden to watch {} in namespace {}, skip it until access is granted",
+                                kind,
+                                ns.as_deref().unwrap_or("*")
+                            );
+                        }
+                        forbidden = true;
+                    }
+                    Err(e) => error!("watch {} event failed, err: {:?}", kind, e),
+                }
+            }
+            producer.shut_down();
+            Ok::<(), Error>(())
+        }));
+
+        let kind = gvk.kind.clone();
+        let versions = Arc::clone(&self.store);
+        watchers.push(tokio::spawn(async move {
+            let handler = factory.build(object_meta, client, s);
+            while let Some((key, (seq, e))) = queue.get().await {
+                match handler.process(e.clone()).await {
+                    Ok(()) => {
+                        queue.forget(&key);
+                        let mut progress = progress.lock().unwrap();
+                        if let Some(rv) = progress.processed(&key, seq) {
+                            save_version(&**versions, &version_key, &rv);
+                        }
+                    }
+                    Err(err) => {
+                        warn!(
+                            "process {} {} failed {} times, requeue it, err: {:?}",
+                            kind,
+                            key,
+                            queue.retries(&key) + 1,
+                            err
+                        );
+                        queue.add_rate_limited(key.clone(), (seq, e));
+                    }
+                }
+                queue.done(&key);
+            }
+            Ok::<(), Error>(())
+        }));
+        watchers
+    }
+
+    // kinds without a registered factory fall back to the dynamic controller
     async fn dispatcher(&self, gvk: &GroupVersionKind) -> Box<dyn EventHandlerFactory> {
-        SCHEMA.lock().unwrap().get(gvk).unwrap().clone_box()
+        match SCHEMA.lock().unwrap().get(gvk) {
+            Some(factory) => factory.clone_box(),
+            None => DynamicControllerFactory::new_box(),
+        }
+    }
+}
+
+// The streams of a kind by namespace, the cluster wide stream is kept under
+// None. Kinds that can not be listed cluster wide follow the listable
+// namespaces unless the namespaces are configured.
+struct KindWatch {
+    streams: HashMap<Option<String>, AbortOnDrop>,
+    follows: bool,
+}
+
+impl KindWatch {
+    async fn stop(self) {
+        for (_, streams) in self.streams {
+            streams.stop().await;
+        }
+    }
+}
+
+// Aborts the tasks of a stream once dropped, so a namespace or kind that is gone
+// or a whole cluster whose watch future is dropped stops watching. Stopping
+// waits for the tasks as well, nothing is written to the store afterwards.
+struct AbortOnDrop(Vec<JoinHandle<Result<()>>>);
+
+impl AbortOnDrop {
+    async fn stop(mut self) {
+        let tasks = std::mem::take(&mut self.0);
+        for w in &tasks {
+            w.abort();
+        }
+        for w in tasks {
+            let _ = w.await;
+        }
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for w in &self.0 {
+            w.abort();
+        }
+    }
+}
+
+// Core kinds come first, so they win a directory over same named kinds of
+// other groups.
+fn by_group(
+    discovered: HashMap<GroupVersionKind, ApiConfig>,
+) -> Vec<(GroupVersionKind, ApiConfig)> {
+    let mut discovered: Vec<_> = discovered.into_iter().collect();
+    discovered.sort_by(|(a, _), (b, _)| {
+        (!a.group.is_empty(), &a.group, &a.kind).cmp(&(!b.group.is_empty(), &b.group, &b.kind))
+    });
+    discovered
+}
+
+// Versions are kept per watch stream, a changed selector starts over with a list.
+fn stream_key(meta: &ClusterObjectMeta, namespace: Option<&str>, lp: &ListParams) -> String {
+    format!(
+        "{}{}?{};{}",
+        get_resource_index_prefix(meta),
+        namespace.unwrap_or_default(),
+        lp.label_selector.as_deref().unwrap_or_default(),
+        lp.field_selector.as_deref().unwrap_or_default()
+    )
+}
+
+fn is_forbidden(e: &watcher::Error) -> bool {
+    match e {
+        watcher::Error::InitialListFailed(kube::Error::Api(resp))
+        | watcher::Error::WatchStartFailed(kube::Error::Api(resp))
+        | watcher::Error::WatchFailed(kube::Error::Api(resp))
+        | watcher::Error::WatchError(resp) => resp.code == 403,
+        _ => false,
+    }
+}
+
+// Saved under the progress lock, so a newer version is never overwritten.
+fn save_version(store: &dyn Storage, key: &str, rv: &str) {
+    if let Err(e) = store.set_watch_state(key, rv) {
+        error!("save resource version failed, err: {:?}", e);
+    }
+}
+
+// The objects a stream queued so far. A relist is turned into an event per
+// listed object and a delete for every known object missing from it, so every
+// event is queued under its object and keeps its order with the watch events
+// of the same object. Objects known in a newer version than the list are kept.
+struct Known(HashMap<String, DynamicObject>);
+
+impl Known {
+    // Starts from the mounted objects, only those of the namespace if the
+    // stream watches a single one. Objects without a version are placeholders
+    // of other kinds, which no list of this one removes.
+    fn new(objs: Vec<DynamicObject>, namespace: Option<&str>) -> Known {
+        Known(
+            objs.into_iter()
+                .filter(|o| o.metadata.resource_version.is_some())
+                .filter(|o| namespace.is_none() || o.metadata.namespace.as_deref() == namespace)
+                .map(|o| (object_key(&o), skeleton(&o)))
+                .collect(),
+        )
+    }
+
+    fn expand(&mut self, e: watcher::Event<DynamicObject>, rv: &str) -> Vec<(String, ObjectEvent)> {
+        match e {
+            watcher::Event::Applied(o) => {
+                let key = object_key(&o);
+                self.0.insert(key.clone(), skeleton(&o));
+                vec![(key, ObjectEvent::Applied(o))]
+            }
+            watcher::Event::Deleted(o) => {
+                let key = object_key(&o);
+                self.0.remove(&key);
+                vec![(key, ObjectEvent::Deleted(o))]
+            }
+            watcher::Event::Restarted(objs) => {
+                let mut events = vec![];
+                let mut known = HashMap::with_capacity(objs.len());
+                for o in objs {
+                    let key = object_key(&o);
+                    match self.0.remove(&key) {
+                        Some(newer) if is_newer(&newer, o.metadata.resource_version.as_deref()) => {
+                            known.insert(key, newer);
+                        }
+                        _ => {
+                            known.insert(key.clone(), skeleton(&o));
+                            events.push((key, ObjectEvent::Applied(o)));
+                        }
+                    }
+                }
+                for (key, o) in self.0.drain() {
+                    if is_newer(&o, Some(rv)) {
+                        known.insert(key, o);
+                    } else {
+                        events.push((key, ObjectEvent::Deleted(o)));
+                    }
+                }
+                self.0 = known;
+                events
+            }
+        }
+    }
+}
+
+// Versions are opaque to clients, but the api server hands out increasing
+// numbers. Anything else is never newer, so the list wins.
+fn is_newer(o: &DynamicObject, rv: Option<&str>) -> bool {
+    let version = |rv: Option<&str>| rv.and_then(|rv| rv.parse::<u64>().ok());
+    match (version(o.metadata.resource_version.as_deref()), version(rv)) {
+        (Some(known), Some(listed)) => known > listed,
+        _ => false,
+    }
+}
+
+// Enough of the object to delete it again and to tell its version.
+fn skeleton(o: &DynamicObject) -> DynamicObject {
+    DynamicObject {
+        types: o.types.clone(),
+        metadata: ObjectMeta {
+            name: o.metadata.name.clone(),
+            namespace: o.metadata.namespace.clone(),
+            resource_version: o.metadata.resource_version.clone(),
+            ..Default::default()
+        },
+        data: Default::default(),
+    }
+}
+
+// Objects are queued by namespace and name.
+fn object_key(o: &DynamicObject) -> String {
+    match (&o.metadata.namespace, &o.metadata.name) {
+        (Some(ns), Some(name)) => format!("{}/{}", ns, name),
+        (None, Some(name)) => name.clone(),
+        _ => String::new(),
     }
 }
@@ -3,17 +3,22 @@ pub mod config;
 pub mod controller;
 pub mod db;
 pub mod error;
+pub mod format;
 pub mod fuse;
 pub mod kube;
+pub mod view;
 
 #[macro_use]
 extern crate lazy_static;
 use ::kube::discovery::Scope;
 use ::kube::{
-    core::DynamicObject, core::GroupVersionKind, discovery::ApiCapabilities, runtime::watcher,
+    core::DynamicObject,
+    core::GroupVersionKind,
+    discovery::{ApiCapabilities, ApiResource},
     Client,
 };
 use async_trait::async_trait;
+use config::View;
 use controller::{NamespaceControllerFactory, PodControllerFactory};
 use db::Storage;
 use sled::IVec;
@@ -39,12 +44,25 @@ impl<'a> ClusterObject<'a> {
 pub struct ClusterObjectMeta {
     pub cluster: String,
     pub gvk: GroupVersionKind,
+    pub plural: String,
     pub caps: ApiCapabilities,
+    pub view: View,
 }
 
 impl ClusterObjectMeta {
-    pub fn new(cluster: String, gvk: GroupVersionKind, caps: ApiCapabilities) -> ClusterObjectMeta {
-        ClusterObjectMeta { cluster, gvk, caps }
+    pub fn new(
+        cluster: String,
+        ar: &ApiResource,
+        caps: ApiCapabilities,
+        view: View,
+    ) -> ClusterObjectMeta {
+        ClusterObjectMeta {
+            cluster,
+            gvk: GroupVersionKind::gvk(&ar.group, &ar.version, &ar.kind),
+            plural: ar.plural.clone(),
+            caps,
+            view,
+        }
     }
 }
 
@@ -52,8 +70,7 @@ impl<'a> TryFrom<&ClusterObject<'a>> for IVec {
     type Error = error::Error;
 
     fn try_from(value: &ClusterObject<'a>) -> std::result::Result<Self, Self::Error> {
-        let obj_data = &*serde_yaml::to_string(value.obj)?;
-        Ok(obj_data.into())
+        value.meta.view.format().render(&view::shown(value))
     }
 }
 
@@ -70,13 +87,20 @@ pub trait FactoryClone {
     fn clone_box(&self) -> Box<dyn EventHandlerFactory>;
 }
 
+// A change of a single object. The watcher turns every relist into these, so
+// handlers never see a whole list.
+#[derive(Clone, Debug)]
+pub enum ObjectEvent {
+    Applied(DynamicObject),
+    Deleted(DynamicObject),
+}
+
 #[async_trait]
 pub trait EventHandler: Send + Sync {
-    async fn process(&self, e: watcher::Event<DynamicObject>) -> Result<()>;
+    async fn process(&self, e: ObjectEvent) -> Result<()>;
 }
 
 lazy_static! {
-    pub static ref INODE_NUM: AtomicU64 = AtomicU64::new(1);
     pub static ref FILE_HANDLE_NUM: AtomicU64 = AtomicU64::new(1);
     pub static ref SCHEMA: Mutex<HashMap<GroupVersionKind, Box<dyn EventHandlerFactory>>> = {
         let mut schema = HashMap::new();
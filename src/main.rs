@@ -1,6 +1,13 @@
 use clap::Parser;
 use fuser::MountOption;
-use kufu::{args::Args, config::load, fuse::Fs, kube::watcher};
+use kufu::{
+    args::Args,
+    config::{load, Config},
+    db::{FSManger, MemoryDb, SledDb, Storage},
+    fuse::Fs,
+    kube::{ClusterManager, WatchOptions},
+};
+use std::time::Duration;
 use tracing::info;
 
 #[tokio::main]
@@ -9,34 +16,55 @@ async fn main() {
     let args = Args::parse();
     let kufu_config = load(args.config_path).unwrap();
 
-    let config = &kufu_config.kube_configs.unwrap()[0];
-    let store = kufu::db::SledDb::new(&kufu_config.mount.data_path).unwrap();
+    match &kufu_config.mount.data_path {
+        Some(data_path) => {
+            serve(
+                SledDb::new(data_path).unwrap(),
+                kufu_config,
+                args.cluster_name,
+            )
+            .await
+        }
+        None => serve(MemoryDb::new(), kufu_config, args.cluster_name).await,
+    }
+}
 
-    let mut watcher = watcher::Watcher::new(
-        kufu_config.resources.unwrap().clone(),
-        config,
-        Box::new(store.clone()),
-    )
-    .await
-    .unwrap();
+async fn serve<S>(store: S, kufu_config: Config, cluster_name: String)
+where
+    S: Storage + FSManger + Clone + 'static,
+{
+    let mut manager = ClusterManager::new(
+        kufu_config.kube_configs.clone().unwrap_or_default(),
+        cluster_name,
+        WatchOptions {
+            resources: kufu_config.resources.clone().unwrap_or_default(),
+            resync_period: kufu_config.resync_period.map(Duration::from_secs),
+            discovery: kufu_config.discovery.clone(),
+            view: kufu_config.mount.view.clone(),
+        },
+        store.clone(),
+        kufu_config.mount.path.clone(),
+    );
 
-    let client = watcher.client.clone();
     let options = vec![
         MountOption::FSName("kufu".to_string()),
         MountOption::AllowOther,
         MountOption::AutoUnmount,
     ];
 
-    let kufu_fs = Fs::new(client, store, kufu_config.mount.path.clone());
+    let kufu_fs = Fs::new(manager.clusters(), store, &kufu_config.mount);
     match kufu_fs.init() {
         Ok(()) => info!("success init kufu fs"),
         Err(e) => panic!("fail to init kufu fs, err: {:?}", e),
     }
 
-    tokio::spawn(async move {
-        watcher.build_api_pool().await.unwrap();
-        watcher.watch().await.unwrap();
-    });
+    manager.start().await.unwrap();
+    tokio::spawn(manager.run());
 
-    fuser::mount2(kufu_fs, &kufu_config.mount.path, &options).unwrap();
+    // fuse callbacks block on api calls, so keep the session off the async workers
+    let mount_path = kufu_config.mount.path.clone();
+    tokio::task::spawn_blocking(move || fuser::mount2(kufu_fs, mount_path, &options))
+        .await
+        .unwrap()
+        .unwrap();
 }
@@ -0,0 +1,359 @@
+use crate::config::Format;
+use crate::db::utils::{get_resource_full_key, is_namespace};
+use crate::error::Error::InvalidClusterObject;
+use crate::{ClusterObject, ClusterObjectMeta, Result};
+use k8s_openapi::ByteString;
+use kube::core::DynamicObject;
+use serde_json::{Map, Value};
+use sled::IVec;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+const LABELS: &str = "metadata/labels";
+const ANNOTATIONS: &str = "metadata/annotations";
+const NEAT: &str = "neat";
+const LAST_APPLIED: &str = "kubectl.kubernetes.io/last-applied-configuration";
+const SECRET_DATA: &str = "data/";
+// not valid base64, so no secret value can be mistaken for it
+pub const REDACTED: &str = "<redacted>";
+// files of a secret belong to the mounting user, the decoded keys are read only
+pub const SECRET_MODE: u16 = 0o600;
+pub const SECRET_KEY_MODE: u16 = 0o400;
+
+// A file rendered from the object and mounted next to the object file or in
+// its view directory. Edits of a part are merged into the object, other files
+// hold the whole object. Files with a mode are only accessible to the mounting
+// user.
+pub struct ViewFile {
+    pub path: String,
+    pub content: IVec,
+    pub part: Option<String>,
+    pub mode: Option<u16>,
+}
+
+// Renders the object in the alternate formats of the view and as neat file.
+pub fn alternates(cluster_obj: &ClusterObject) -> Result<Vec<ViewFile>> {
+    let paths = alternate_paths(cluster_obj);
+    let view = &cluster_obj.meta.view;
+    let obj = shown(cluster_obj);
+    let mode = is_secret(cluster_obj.meta).then_some(SECRET_MODE);
+    let mut files = vec![];
+    for ((path, _), format) in paths.into_iter().zip(view.alternate_formats()) {
+        files.push(ViewFile {
+            path,
+            content: format.render(&obj)?,
+            part: None,
+            mode,
+        });
+    }
+    if view.neat {
+        files.push(ViewFile {
+            path: neat_path(cluster_obj),
+            content: view.format().render(&neat(&obj))?,
+            part: Some(NEAT.to_string()),
+            mode,
+        });
+    }
+    Ok(files)
+}
+
+// The paths of the alternate files, and whether the file is a part merged into
+// the object like the neat file.
+pub fn alternate_paths(cluster_obj: &ClusterObject) -> Vec<(String, bool)> {
+    let key = get_resource_full_key(cluster_obj);
+    let view = &cluster_obj.meta.view;
+    let mut paths: Vec<(String, bool)> = view
+        .alternate_formats()
+        .iter()
+        .map(|format| (format!("{}.{}", key, format.extension()), false))
+        .collect();
+    if view.neat {
+        paths.push((neat_path(cluster_obj), true));
+    }
+    paths
+}
+
+fn neat_path(cluster_obj: &ClusterObject) -> String {
+    let extension = cluster_obj.meta.view.format().extension();
+    format!(
+        "{}.{}.{}",
+        get_resource_full_key(cluster_obj),
+        NEAT,
+        extension
+    )
+}
+
+// Strips what the server manages from the object, like kubectl-neat does.
+pub fn neat(obj: &DynamicObject) -> DynamicObject {
+    let mut neat = obj.clone();
+    let meta = &mut neat.metadata;
+    meta.managed_fields = None;
+    meta.resource_version = None;
+    meta.uid = None;
+    meta.creation_timestamp = None;
+    meta.generation = None;
+    meta.self_link = None;
+    if let Some(annotations) = meta.annotations.as_mut() {
+        annotations.remove(LAST_APPLIED);
+        if annotations.is_empty() {
+            meta.annotations = None;
+        }
+    }
+    if let Some(fields) = neat.data.as_object_mut() {
+        fields.remove("status");
+    }
+    neat
+}
+
+// Puts back what neat stripped from the full object into the edited one, so
+// the replace keeps them and fails if the object changed in the meantime.
+fn unneat(mut edited: DynamicObject, full: &DynamicObject) -> Result<DynamicObject> {
+    if edited.types != full.types
+        || edited.metadata.name != full.metadata.name
+        || edited.metadata.namespace != full.metadata.namespace
+    {
+        return Err(InvalidClusterObject(
+            "apiVersion, kind, name and namespace of a neat file can not be changed".to_string(),
+        ));
+    }
+    let meta = &mut edited.metadata;
+    meta.managed_fields = full.metadata.managed_fields.clone();
+    meta.resource_version = full.metadata.resource_version.clone();
+    meta.uid = full.metadata.uid.clone();
+    meta.creation_timestamp = full.metadata.creation_timestamp.clone();
+    meta.generation = full.metadata.generation;
+    meta.self_link = full.metadata.self_link.clone();
+    let last_applied = full
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(LAST_APPLIED));
+    if let Some(last_applied) = last_applied {
+        meta.annotations
+            .get_or_insert_with(Default::default)
+            .insert(LAST_APPLIED.to_string(), last_applied.clone());
+    }
+    if let Some(status) = full.data.get("status") {
+        if !edited.data.is_object() {
+            edited.data = Value::Object(Map::new());
+        }
+        let fields = edited.data.as_object_mut().unwrap();
+        fields.insert("status".to_string(), status.clone());
+    }
+    Ok(edited)
+}
+
+// Returns the directory the object gets next to its file, which holds the keys
+// of a secret or the split view. Namespaces have none, their directory already
+// holds the namespaced objects.
+pub fn view_dir(cluster_obj: &ClusterObject) -> Option<String> {
+    let split = cluster_obj.meta.view.split && !is_namespace(cluster_obj);
+    if !split && !is_secret(cluster_obj.meta) {
+        return None;
+    }
+    Some(get_resource_full_key(cluster_obj))
+}
+
+// Renders the files of the view directory with paths relative to it, secrets
+// are never split.
+pub fn dir_view(cluster_obj: &ClusterObject) -> Result<Vec<ViewFile>> {
+    if is_secret(cluster_obj.meta) {
+        return secret_keys(cluster_obj);
+    }
+    let obj = shown(cluster_obj);
+    let parts = split(&obj, cluster_obj.meta.view.format())?;
+    Ok(parts
+        .into_iter()
+        .map(|(path, content)| ViewFile {
+            part: Some(path.clone()),
+            path,
+            content,
+            mode: None,
+        })
+        .collect())
+}
+
+// One file per key of the secret with the decoded value, or the redaction
+// marker unless values are revealed.
+fn secret_keys(cluster_obj: &ClusterObject) -> Result<Vec<ViewFile>> {
+    let data = match cluster_obj.obj.data.get("data") {
+        Some(data) => serde_json::from_value(data.clone())?,
+        None => BTreeMap::<String, ByteString>::new(),
+    };
+    let redacted = is_redacted(cluster_obj.meta);
+    Ok(data
+        .into_iter()
+        .map(|(key, value)| ViewFile {
+            part: Some(format!("{}{}", SECRET_DATA, key)),
+            path: key,
+            content: match redacted {
+                true => format!("{}\n", REDACTED).as_str().into(),
+                false => value.0.into(),
+            },
+            mode: Some(SECRET_KEY_MODE),
+        })
+        .collect())
+}
+
+// Every file of a secret is private, the values may be revealed in any of them.
+pub fn is_secret(meta: &ClusterObjectMeta) -> bool {
+    meta.gvk.group.is_empty() && meta.gvk.kind == "Secret"
+}
+
+// Values of secrets are only mounted if the view reveals them.
+pub fn is_redacted(meta: &ClusterObjectMeta) -> bool {
+    is_secret(meta) && !meta.view.reveal_secrets
+}
+
+// Returns the object as every file of the view shows it.
+pub fn shown<'a>(cluster_obj: &ClusterObject<'a>) -> Cow<'a, DynamicObject> {
+    if !is_redacted(cluster_obj.meta) {
+        return Cow::Borrowed(cluster_obj.obj);
+    }
+    let mut obj = cluster_obj.obj.clone();
+    for field in ["data", "stringData"] {
+        if let Some(values) = obj.data.get_mut(field).and_then(Value::as_object_mut) {
+            for value in values.values_mut() {
+                *value = Value::String(REDACTED.to_string());
+            }
+        }
+    }
+    // kubectl keeps the applied secret in plain text there
+    if let Some(annotations) = obj.metadata.annotations.as_mut() {
+        annotations.remove(LAST_APPLIED);
+    }
+    Cow::Owned(obj)
+}
+
+// Takes the redacted values from the live object before the edited one is
+// sent, values that are gone from it are dropped.
+pub fn unredact(obj: &mut DynamicObject, live: &DynamicObject) {
+    for field in ["data", "stringData"] {
+        let live_values = live.data.get(field).and_then(Value::as_object);
+        if let Some(values) = obj.data.get_mut(field).and_then(Value::as_object_mut) {
+            values.retain(|key, value| {
+                if value.as_str() != Some(REDACTED) {
+                    return true;
+                }
+                match live_values.and_then(|live| live.get(key)) {
+                    Some(live) => {
+                        *value = live.clone();
+                        true
+                    }
+                    None => false,
+                }
+            });
+        }
+    }
+    let last_applied = live
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(LAST_APPLIED));
+    if let Some(last_applied) = last_applied {
+        obj.metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .entry(LAST_APPLIED.to_string())
+            .or_insert_with(|| last_applied.clone());
+    }
+}
+
+// Renders the parts of the split view with paths relative to its directory.
+// Every top level field next to the type and metadata gets a file, which is
+// spec and status for most kinds and data for config maps.
+pub fn split(obj: &DynamicObject, format: Format) -> Result<Vec<(String, IVec)>> {
+    let mut parts = vec![
+        (
+            LABELS.to_string(),
+            render_map(&obj.metadata.labels, format)?,
+        ),
+        (
+            ANNOTATIONS.to_string(),
+            render_map(&obj.metadata.annotations, format)?,
+        ),
+    ];
+    if let Some(fields) = obj.data.as_object() {
+        for (field, value) in fields {
+            let content = format.serializer().encode(value)?;
+            parts.push((format!("{}.{}", field, format.extension()), content.into()));
+        }
+    }
+    Ok(parts)
+}
+
+// Puts the edited part back into the full object, an emptied field file drops
+// the field.
+pub fn merge(obj: &mut DynamicObject, part: &str, data: &[u8], format: Format) -> Result<()> {
+    match part {
+        NEAT => *obj = unneat(format.parse(data)?, obj)?,
+        _ if part.starts_with(SECRET_DATA) => {
+            merge_secret_key(obj, &part[SECRET_DATA.len()..], data)?
+        }
+        LABELS => obj.metadata.labels = parse_map(data, format)?,
+        ANNOTATIONS => obj.metadata.annotations = parse_map(data, format)?,
+        _ => {
+            let field = match part.strip_suffix(&format!(".{}", format.extension())) {
+                Some(field) if !field.contains('/') => field,
+                _ => return Err(InvalidClusterObject(format!("unknown view part {}", part))),
+            };
+            let value = match is_blank(data) {
+                true => Value::Null,
+                false => format.serializer().decode(data)?,
+            };
+            if !obj.data.is_object() {
+                obj.data = Value::Object(Map::new());
+            }
+            let fields = obj.data.as_object_mut().unwrap();
+            match value {
+                Value::Null => fields.remove(field),
+                value => fields.insert(field.to_string(), value),
+            };
+        }
+    }
+    Ok(())
+}
+
+// An unchanged redaction marker keeps the value, whatever it is.
+fn merge_secret_key(obj: &mut DynamicObject, key: &str, data: &[u8]) -> Result<()> {
+    if data.trim_ascii() == REDACTED.as_bytes() {
+        return Ok(());
+    }
+    if !obj.data.is_object() {
+        obj.data = Value::Object(Map::new());
+    }
+    let fields = obj.data.as_object_mut().unwrap();
+    let values = fields
+        .entry("data")
+        .or_insert_with(|| Value::Object(Map::new()));
+    match values.as_object_mut() {
+        Some(values) => {
+            let value = serde_json::to_value(ByteString(data.to_vec()))?;
+            values.insert(key.to_string(), value);
+            Ok(())
+        }
+        None => Err(InvalidClusterObject("data of secret is no map".to_string())),
+    }
+}
+
+fn render_map(map: &Option<BTreeMap<String, String>>, format: Format) -> Result<IVec> {
+    match map {
+        Some(map) if !map.is_empty() => {
+            let content = format.serializer().encode(&serde_json::to_value(map)?)?;
+            Ok(content.into())
+        }
+        _ => Ok(IVec::default()),
+    }
+}
+
+fn parse_map(data: &[u8], format: Format) -> Result<Option<BTreeMap<String, String>>> {
+    if is_blank(data) {
+        return Ok(None);
+    }
+    let value = format.serializer().decode(data)?;
+    Ok(Some(serde_json::from_value(value)?))
+}
+
+fn is_blank(data: &[u8]) -> bool {
+    data.iter().all(u8::is_ascii_whitespace)
+}
@@ -0,0 +1,1002 @@
+// A stand-in for the kubernetes api server and a mounted cluster on top of it.
+// The server keeps the objects of a few kinds in memory and serves discovery,
+// list, watch and writes for them. Tests script the cluster by applying and
+// deleting objects, open watches get the events as they happen.
+#![allow(dead_code)]
+
+use kube::{
+    config::{KubeConfigOptions, Kubeconfig},
+    core::TypeMeta,
+    Client,
+};
+use kufu::config::{self, KubeConfig, Resource, View};
+use kufu::db::{Backend, FSManger, MemoryBackend, MemoryDb, SledDb, Store};
+use kufu::fuse::Fs;
+use kufu::kube::{ClusterPool, WatchOptions, Watcher};
+use libc::ENOENT;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
+
+pub const CLUSTER: &str = "default";
+pub const MOUNT_POINT: &str = "/kufu-test";
+
+#[derive(Clone, Debug)]
+pub struct FakeKind {
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+    pub plural: String,
+    pub namespaced: bool,
+}
+
+impl FakeKind {
+    pub fn new(api_version: &str, kind: &str, plural: &str, namespaced: bool) -> FakeKind {
+        let (group, version) = match api_version.split_once('/') {
+            Some((group, version)) => (group, version),
+            None => ("", api_version),
+        };
+        FakeKind {
+            group: group.to_string(),
+            version: version.to_string(),
+            kind: kind.to_string(),
+            plural: plural.to_string(),
+            namespaced,
+        }
+    }
+
+    pub fn pod() -> FakeKind {
+        FakeKind::new("v1", "Pod", "pods", true)
+    }
+
+    pub fn namespace() -> FakeKind {
+        FakeKind::new("v1", "Namespace", "namespaces", false)
+    }
+
+    pub fn secret() -> FakeKind {
+        FakeKind::new("v1", "Secret", "secrets", true)
+    }
+
+    pub fn api_version(&self) -> String {
+        match self.group.as_str() {
+            "" => self.version.clone(),
+            group => format!("{}/{}", group, self.version),
+        }
+    }
+
+    pub fn resource(&self) -> Resource {
+        Resource {
+            types: TypeMeta {
+                api_version: self.api_version(),
+                kind: self.kind.clone(),
+            },
+            label_selector: None,
+            field_selector: None,
+            namespaces: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Event {
+    rv: u64,
+    kind: usize,
+    action: &'static str,
+    object: Value,
+}
+
+struct State {
+    kinds: Vec<FakeKind>,
+    // (kind, namespace, name)
+    objects: BTreeMap<(usize, String, String), Value>,
+    rv: u64,
+    log: Vec<Event>,
+    // watches from an older version are answered with 410 gone
+    compacted: u64,
+    // kinds that can only be listed namespace by namespace
+    forbidden: Vec<usize>,
+    // kinds left out of discovery
+    withdrawn: Vec<usize>,
+    requests: Vec<String>,
+    events: broadcast::Sender<Option<Event>>,
+}
+
+pub struct FakeApiServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for FakeApiServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl FakeApiServer {
+    pub async fn start(kinds: Vec<FakeKind>) -> FakeApiServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            kinds,
+            objects: BTreeMap::new(),
+            rv: 1,
+            log: vec![],
+            compacted: 0,
+            forbidden: vec![],
+            withdrawn: vec![],
+            requests: vec![],
+            events: broadcast::channel(1024).0,
+        }));
+        let accept_state = Arc::clone(&state);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, Arc::clone(&accept_state)));
+            }
+        });
+        FakeApiServer { addr, state, task }
+    }
+
+    // Starts a server with the dev namespace holding the objects, namespaces
+    // are served next to the given kinds.
+    pub async fn with_dev(kinds: &[FakeKind], objects: Vec<Value>) -> FakeApiServer {
+        let mut served = vec![FakeKind::namespace()];
+        served.extend_from_slice(kinds);
+        let server = FakeApiServer::start(served).await;
+        server.apply(namespace("dev"));
+        for object in objects {
+            server.apply(object);
+        }
+        server
+    }
+
+    pub fn kinds(&self) -> Vec<FakeKind> {
+        self.state.lock().unwrap().kinds.clone()
+    }
+
+    // A kubeconfig pointing at the server, given inline like in the kufu config.
+    pub fn kubeconfig(&self) -> KubeConfig {
+        kubeconfig(&self.addr.to_string())
+    }
+
+    // Adds or replaces the object and sends the event to the open watches.
+    pub fn apply(&self, object: Value) {
+        let mut state = self.state.lock().unwrap();
+        let kind = state.kind_of(&object);
+        state.apply(kind, object);
+    }
+
+    pub fn delete(&self, object: Value) {
+        let mut state = self.state.lock().unwrap();
+        let kind = state.kind_of(&object);
+        state.delete(&object_key(kind, &object));
+    }
+
+    // The object as the server has it, looked up by the kind, namespace and
+    // name of the given one.
+    pub fn get(&self, object: &Value) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        let kind = state.kind_of(object);
+        state.objects.get(&object_key(kind, object)).cloned()
+    }
+
+    // Deletes the object without telling the watches, like an event that got
+    // lost while the watch was down.
+    pub fn delete_quietly(&self, object: Value) {
+        let mut state = self.state.lock().unwrap();
+        let kind = state.kind_of(&object);
+        state.objects.remove(&object_key(kind, &object));
+        state.rv += 1;
+    }
+
+    // Forgets the events so far and closes the open watches, resuming from an
+    // older version then needs a relist.
+    pub fn compact(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.compacted = state.rv;
+        state.log.clear();
+        let _ = state.events.send(None);
+    }
+
+    // Closes the open watches, they have to be started again by the client.
+    pub fn close_watches(&self) {
+        let _ = self.state.lock().unwrap().events.send(None);
+    }
+
+    // The kind can not be listed or watched cluster wide anymore.
+    pub fn forbid_cluster_wide(&self, kind: &FakeKind) {
+        let mut state = self.state.lock().unwrap();
+        let kind = state.kind_index(&kind.api_version(), &kind.kind);
+        state.forbidden.push(kind);
+    }
+
+    // Discovery stops serving the kind, like a crd that got uninstalled.
+    pub fn withdraw(&self, kind: &FakeKind) {
+        let mut state = self.state.lock().unwrap();
+        let kind = state.kind_index(&kind.api_version(), &kind.kind);
+        state.withdrawn.push(kind);
+    }
+
+    // The requests served so far as "path?query".
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl State {
+    fn kind_index(&self, api_version: &str, kind: &str) -> usize {
+        self.kinds
+            .iter()
+            .position(|k| k.api_version() == api_version && k.kind == kind)
+            .unwrap_or_else(|| panic!("{} {} is not served", api_version, kind))
+    }
+
+    fn is_kind(&self, kind: usize, object: &Value) -> bool {
+        let kind = &self.kinds[kind];
+        object["apiVersion"].as_str() == Some(&kind.api_version())
+            && object["kind"].as_str() == Some(&kind.kind)
+    }
+
+    fn kind_of(&self, object: &Value) -> usize {
+        self.kind_index(
+            object["apiVersion"].as_str().unwrap(),
+            object["kind"].as_str().unwrap(),
+        )
+    }
+
+    fn apply(&mut self, kind: usize, mut object: Value) -> Value {
+        let key = object_key(kind, &object);
+        self.rv += 1;
+        object["metadata"]["resourceVersion"] = json!(self.rv.to_string());
+        let action = match self.objects.insert(key, object.clone()) {
+            Some(_) => "MODIFIED",
+            None => "ADDED",
+        };
+        self.publish(kind, action, object.clone());
+        object
+    }
+
+    fn delete(&mut self, key: &(usize, String, String)) -> Option<Value> {
+        let mut object = self.objects.remove(key)?;
+        self.rv += 1;
+        object["metadata"]["resourceVersion"] = json!(self.rv.to_string());
+        self.publish(key.0, "DELETED", object.clone());
+        Some(object)
+    }
+
+    fn publish(&mut self, kind: usize, action: &'static str, object: Value) {
+        let event = Event {
+            rv: self.rv,
+            kind,
+            action,
+            object,
+        };
+        self.log.push(event.clone());
+        let _ = self.events.send(Some(event));
+    }
+}
+
+fn object_key(kind: usize, object: &Value) -> (usize, String, String) {
+    let metadata = &object["metadata"];
+    (
+        kind,
+        metadata["namespace"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        metadata["name"].as_str().unwrap().to_string(),
+    )
+}
+
+pub fn kubeconfig(addr: &str) -> KubeConfig {
+    let raw: Kubeconfig = serde_yaml::from_str(&format!(
+        r#"
+apiVersion: v1
+kind: Config
+clusters:
+- name: fake
+  cluster:
+    server: http://{}
+users:
+- name: fake
+  user: {{}}
+contexts:
+- name: fake
+  context:
+    cluster: fake
+    user: fake
+    namespace: default
+current-context: fake
+"#,
+        addr
+    ))
+    .unwrap();
+    KubeConfig {
+        name: None,
+        config_path: None,
+        raw: Some(raw),
+        contexts: None,
+        namespaces: None,
+    }
+}
+
+// A client for the cluster behind the kubeconfig.
+pub async fn client(kubeconfig: &KubeConfig) -> Client {
+    let options = KubeConfigOptions::default();
+    let config = kube::Config::from_custom_kubeconfig(kubeconfig.load().unwrap(), &options)
+        .await
+        .unwrap();
+    Client::try_from(config).unwrap()
+}
+
+pub fn namespace(name: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Namespace",
+        "metadata": {"name": name},
+    })
+}
+
+pub fn pod(namespace: &str, name: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": {"name": name, "namespace": namespace},
+        "spec": {"containers": [{"name": "app", "image": "nginx"}]},
+    })
+}
+
+pub fn object(kind: &FakeKind, namespace: Option<&str>, name: &str) -> Value {
+    let mut object = json!({
+        "apiVersion": kind.api_version(),
+        "kind": kind.kind,
+        "metadata": {"name": name},
+    });
+    if let Some(ns) = namespace {
+        object["metadata"]["namespace"] = json!(ns);
+    }
+    object
+}
+
+// The secret db in dev, its password is "hunter2". kubectl keeps the applied
+// secret in plain text in an annotation.
+pub fn db_secret() -> Value {
+    let mut secret = object(&FakeKind::secret(), Some("dev"), "db");
+    secret["metadata"]["annotations"] = json!({
+        "kubectl.kubernetes.io/last-applied-configuration": r#"{"data":{"password":"aHVudGVyMg=="}}"#,
+    });
+    secret["data"] = json!({"password": "aHVudGVyMg==", "user": "YWRtaW4="});
+    secret
+}
+
+// The object as a user would write it into a yaml file.
+pub fn yaml(object: &Value) -> String {
+    serde_yaml::to_string(object).unwrap()
+}
+
+// What a list or watch request asks for.
+struct Query {
+    kind: usize,
+    namespace: Option<String>,
+    watch: bool,
+    resource_version: Option<u64>,
+    labels: Vec<(String, String, bool)>,
+}
+
+impl Query {
+    fn matches(&self, kind: usize, object: &Value) -> bool {
+        if kind != self.kind {
+            return false;
+        }
+        let metadata = &object["metadata"];
+        if let Some(ns) = &self.namespace {
+            if metadata["namespace"].as_str() != Some(ns) {
+                return false;
+            }
+        }
+        self.labels
+            .iter()
+            .all(|(key, value, equal)| (metadata["labels"][key].as_str() == Some(value)) == *equal)
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    if stream.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        match stream.read_line(&mut header).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if header == "\r\n" => break,
+            Ok(_) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                }
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    if stream.read_exact(&mut body).await.is_err() {
+        return;
+    }
+    let mut request = request_line.split(' ');
+    let method = request.next().unwrap_or_default().to_string();
+    let target = request.next().unwrap_or_default().to_string();
+    state.lock().unwrap().requests.push(target.clone());
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let params: BTreeMap<String, String> = query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (k.to_string(), decode(v)))
+        .collect();
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = {
+        let mut state = state.lock().unwrap();
+        route(&mut state, &method, &segments, &params, &body)
+    };
+    match response {
+        Route::Json(code, body) => respond(stream.get_mut(), code, &body).await,
+        Route::Watch(query) => watch(stream.get_mut(), &state, query).await,
+    }
+}
+
+enum Route {
+    Json(u16, Value),
+    Watch(Query),
+}
+
+fn route(
+    state: &mut State,
+    method: &str,
+    segments: &[&str],
+    params: &BTreeMap<String, String>,
+    body: &[u8],
+) -> Route {
+    let discovered: Vec<FakeKind> = (0..state.kinds.len())
+        .filter(|kind| !state.withdrawn.contains(kind))
+        .map(|kind| state.kinds[kind].clone())
+        .collect();
+    match segments {
+        ["api"] => return Route::Json(200, api_versions()),
+        ["apis"] => return Route::Json(200, api_groups(&discovered)),
+        _ => {}
+    }
+    let (group, version, rest) = match segments {
+        ["api", version, rest @ ..] => ("", *version, rest),
+        ["apis", group, version, rest @ ..] => (*group, *version, rest),
+        _ => return Route::Json(404, status(404, "NotFound", "unknown path")),
+    };
+    let served = |k: &FakeKind| k.group == group && k.version == version;
+    let (plural, namespace, name) = match rest {
+        [] if discovered.iter().any(served) => {
+            return Route::Json(200, resource_list(&discovered, group, version))
+        }
+        [plural] => (*plural, None, None),
+        [plural, name] => (*plural, None, Some(*name)),
+        ["namespaces", ns, plural] => (*plural, Some(ns.to_string()), None),
+        ["namespaces", ns, plural, name] => (*plural, Some(ns.to_string()), Some(*name)),
+        _ => return Route::Json(404, status(404, "NotFound", "unknown path")),
+    };
+    let kind = match state
+        .kinds
+        .iter()
+        .position(|k| served(k) && k.plural == plural)
+    {
+        Some(kind) => kind,
+        None => return Route::Json(404, status(404, "NotFound", "unknown resource")),
+    };
+    if let Some(name) = name {
+        let key = (kind, namespace.unwrap_or_default(), name.to_string());
+        let (code, object) = object_route(state, method, key, body);
+        return Route::Json(code, object);
+    }
+    if method == "POST" {
+        let (code, object) = create(state, kind, namespace, body);
+        return Route::Json(code, object);
+    }
+    if namespace.is_none() && state.kinds[kind].namespaced && state.forbidden.contains(&kind) {
+        return Route::Json(403, status(403, "Forbidden", "cluster wide access denied"));
+    }
+    let query = Query {
+        kind,
+        namespace,
+        watch: matches!(params.get("watch").map(|w| w.as_str()), Some("true" | "1")),
+        resource_version: params.get("resourceVersion").and_then(|rv| rv.parse().ok()),
+        labels: params
+            .get("labelSelector")
+            .map(|s| parse_selector(s))
+            .unwrap_or_default(),
+    };
+    if query.watch {
+        return Route::Watch(query);
+    }
+    let items: Vec<Value> = state
+        .objects
+        .iter()
+        .filter(|((k, _, _), object)| query.matches(*k, object))
+        .map(|(_, object)| object.clone())
+        .collect();
+    let kind = &state.kinds[kind];
+    Route::Json(
+        200,
+        json!({
+            "apiVersion": kind.api_version(),
+            "kind": format!("{}List", kind.kind),
+            "metadata": {"resourceVersion": state.rv.to_string()},
+            "items": items,
+        }),
+    )
+}
+
+// Reads, replaces or deletes a single object. A replace has to come with the
+// version the server has, like the optimistic locking of the real one.
+fn object_route(
+    state: &mut State,
+    method: &str,
+    key: (usize, String, String),
+    body: &[u8],
+) -> (u16, Value) {
+    let current = match state.objects.get(&key) {
+        Some(current) => current.clone(),
+        None => return (404, status(404, "NotFound", "object not found")),
+    };
+    match method {
+        "GET" => (200, current),
+        "DELETE" => (200, state.delete(&key).unwrap()),
+        "PUT" => {
+            let object: Value = match serde_json::from_slice(body) {
+                Ok(object) => object,
+                Err(_) => return (400, status(400, "BadRequest", "invalid body")),
+            };
+            if !state.is_kind(key.0, &object) || object_key(key.0, &object) != key {
+                return (
+                    400,
+                    status(400, "BadRequest", "object does not match the url"),
+                );
+            }
+            let rv = &object["metadata"]["resourceVersion"];
+            if !rv.is_null() && *rv != current["metadata"]["resourceVersion"] {
+                return (409, status(409, "Conflict", "the object has been modified"));
+            }
+            (200, state.apply(key.0, object))
+        }
+        _ => (405, status(405, "MethodNotAllowed", "method not allowed")),
+    }
+}
+
+fn create(state: &mut State, kind: usize, namespace: Option<String>, body: &[u8]) -> (u16, Value) {
+    let mut object: Value = match serde_json::from_slice(body) {
+        Ok(object) => object,
+        Err(_) => return (400, status(400, "BadRequest", "invalid body")),
+    };
+    if !state.is_kind(kind, &object) {
+        return (
+            400,
+            status(400, "BadRequest", "object does not match the url"),
+        );
+    }
+    if let Some(ns) = namespace {
+        object["metadata"]["namespace"] = json!(ns);
+    }
+    if state.objects.contains_key(&object_key(kind, &object)) {
+        return (409, status(409, "AlreadyExists", "object already exists"));
+    }
+    (201, state.apply(kind, object))
+}
+
+// Sends the events after the requested version, then the live ones until the
+// watches are closed by the test.
+async fn watch(stream: &mut TcpStream, state: &Arc<Mutex<State>>, query: Query) {
+    let watched = {
+        let state = state.lock().unwrap();
+        let since = query.resource_version.unwrap_or(state.rv);
+        match since < state.compacted {
+            true => None,
+            false => {
+                let backlog: Vec<Event> =
+                    state.log.iter().filter(|e| e.rv > since).cloned().collect();
+                Some((backlog, state.events.subscribe()))
+            }
+        }
+    };
+    let (backlog, mut events) = match watched {
+        Some(watched) => watched,
+        None => {
+            let gone = json!({"type": "ERROR", "object": status(410, "Expired", "too old resource version")});
+            let _ = write_head(stream).await;
+            let _ = stream.write_all(format!("{}\n", gone).as_bytes()).await;
+            return;
+        }
+    };
+    if write_head(stream).await.is_err() {
+        return;
+    }
+    for event in backlog {
+        if !send_event(stream, &query, &event).await {
+            return;
+        }
+    }
+    while let Ok(Some(event)) = events.recv().await {
+        if !send_event(stream, &query, &event).await {
+            return;
+        }
+    }
+}
+
+async fn send_event(stream: &mut TcpStream, query: &Query, event: &Event) -> bool {
+    if !query.matches(event.kind, &event.object) {
+        return true;
+    }
+    let line = json!({"type": event.action, "object": event.object});
+    stream
+        .write_all(format!("{}\n", line).as_bytes())
+        .await
+        .is_ok()
+}
+
+async fn write_head(stream: &mut TcpStream) -> std::io::Result<()> {
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n",
+        )
+        .await
+}
+
+async fn respond(stream: &mut TcpStream, code: u16, body: &Value) {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        code,
+        match code {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Not Found",
+        },
+        body.len()
+    );
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(body.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn api_versions() -> Value {
+    json!({
+        "kind": "APIVersions",
+        "versions": ["v1"],
+        "serverAddressByClientCIDRs": [],
+    })
+}
+
+fn api_groups(kinds: &[FakeKind]) -> Value {
+    let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for k in kinds.iter().filter(|k| !k.group.is_empty()) {
+        let versions = groups.entry(&k.group).or_default();
+        if !versions.contains(&k.version.as_str()) {
+            versions.push(&k.version);
+        }
+    }
+    let groups: Vec<Value> = groups
+        .into_iter()
+        .map(|(group, versions)| {
+            let versions: Vec<Value> = versions
+                .iter()
+                .map(|v| json!({"groupVersion": format!("{}/{}", group, v), "version": v}))
+                .collect();
+            json!({
+                "name": group,
+                "versions": versions,
+                "preferredVersion": versions[0],
+            })
+        })
+        .collect();
+    json!({"kind": "APIGroupList", "apiVersion": "v1", "groups": groups})
+}
+
+fn resource_list(kinds: &[FakeKind], group: &str, version: &str) -> Value {
+    let resources: Vec<Value> = kinds
+        .iter()
+        .filter(|k| k.group == group && k.version == version)
+        .map(|k| {
+            json!({
+                "name": k.plural,
+                "singularName": "",
+                "namespaced": k.namespaced,
+                "kind": k.kind,
+                "verbs": ["create", "delete", "get", "list", "patch", "update", "watch"],
+            })
+        })
+        .collect();
+    let group_version = match group {
+        "" => version.to_string(),
+        group => format!("{}/{}", group, version),
+    };
+    json!({
+        "kind": "APIResourceList",
+        "apiVersion": "v1",
+        "groupVersion": group_version,
+        "resources": resources,
+    })
+}
+
+fn status(code: u16, reason: &str, message: &str) -> Value {
+    json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code,
+    })
+}
+
+// "app=web,tier!=db"
+fn parse_selector(selector: &str) -> Vec<(String, String, bool)> {
+    selector
+        .split(',')
+        .filter_map(|term| match term.split_once("!=") {
+            Some((k, v)) => Some((k.to_string(), v.to_string(), false)),
+            None => term
+                .split_once('=')
+                .map(|(k, v)| (k.to_string(), v.trim_start_matches('=').to_string(), true)),
+        })
+        .collect()
+}
+
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// A sled tree in a directory of its own, removed with the last handle.
+pub struct SledDir(PathBuf);
+
+impl SledDir {
+    pub fn new(test: &str) -> SledDir {
+        let dir = std::env::temp_dir().join(format!("kufu-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        SledDir(dir)
+    }
+
+    // sled lets go of the lock from its own threads, shortly after the last
+    // handle of an earlier mount is dropped.
+    pub async fn open(&self) -> SledDb {
+        for _ in 0..500 {
+            if let Ok(db) = SledDb::new(&self.0) {
+                return db;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out opening {}", self.0.display());
+    }
+}
+
+impl Drop for SledDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn mount_config(view: View, delete_namespaces: bool) -> config::Mount {
+    config::Mount {
+        data_path: None,
+        path: MOUNT_POINT.to_string(),
+        propagation_policy: None,
+        grace_period: None,
+        delete_namespaces,
+        view,
+    }
+}
+
+// A cluster mounted from the fake server into a tree, in memory unless the
+// test opens one on disk. The file system writes to the server and the watcher runs until the mount is dropped.
+// Tests which write through the file system block on api calls like the fuse
+// session does, so they need the multi threaded runtime.
+pub struct Mount<B: Backend = MemoryBackend> {
+    pub store: Store<B>,
+    pub fs: Fs<Store<B>>,
+    watch: JoinHandle<()>,
+    stop: Arc<Notify>,
+}
+
+impl<B: Backend> Drop for Mount<B> {
+    fn drop(&mut self) {
+        self.watch.abort();
+    }
+}
+
+impl Mount {
+    pub async fn new(server: &FakeApiServer, kinds: &[FakeKind], view: impl Into<View>) -> Mount {
+        Mount::open(server, kinds, view, MemoryDb::new()).await
+    }
+
+    // Mounts every kind the server serves and waits until the file shows up.
+    pub async fn with_file(server: &FakeApiServer, view: impl Into<View>, path: &str) -> Mount {
+        let mount = Mount::new(server, &server.kinds(), view).await;
+        mount.wait_for(path, |m| m.look_up(path).is_some()).await;
+        mount
+    }
+}
+
+impl<B: Backend> Mount<B> {
+    // Mounts the cluster from the tree of an earlier mount, like a restart of kufu.
+    pub async fn open(
+        server: &FakeApiServer,
+        kinds: &[FakeKind],
+        view: impl Into<View>,
+        store: Store<B>,
+    ) -> Mount<B> {
+        let opts = WatchOptions {
+            resources: kinds.iter().map(|k| k.resource()).collect(),
+            view: view.into(),
+            ..Default::default()
+        };
+        Mount::with_options(server, opts, store).await
+    }
+
+    pub async fn with_options(
+        server: &FakeApiServer,
+        opts: WatchOptions,
+        store: Store<B>,
+    ) -> Mount<B> {
+        let view = opts.view.clone();
+        let clusters = config::clusters(&[server.kubeconfig()], CLUSTER).unwrap();
+        let mut watcher = Watcher::new(
+            CLUSTER.to_string(),
+            &clusters[0],
+            Box::new(store.clone()),
+            opts,
+        )
+        .await
+        .unwrap();
+        let pool = ClusterPool::default();
+        pool.write()
+            .unwrap()
+            .insert(CLUSTER.to_string(), watcher.cluster_api());
+        let fs = Fs::new(pool, store.clone(), &mount_config(view, false));
+        fs.init().unwrap();
+        let root = store.get_inode(MOUNT_POINT.to_string()).unwrap();
+        store.mount_dir(CLUSTER, root).unwrap();
+
+        watcher.build_api_pool().await.unwrap();
+        let stop = watcher.stop_signal();
+        let watch = tokio::spawn(async move {
+            watcher.watch().await.unwrap();
+        });
+        Mount {
+            store,
+            fs,
+            watch,
+            stop,
+        }
+    }
+
+    // Lets the file system delete namespaces, like delete-namespaces: true.
+    pub fn allow_namespace_deletion(&mut self) {
+        let mount = mount_config(self.fs.inner.view.clone(), true);
+        self.fs = Fs::new(self.fs.clusters.clone(), self.store.clone(), &mount);
+    }
+
+    // Stops watching like the cluster manager does and hands back the tree
+    // for the next mount.
+    pub async fn unmount(mut self) -> Store<B> {
+        self.stop.notify_one();
+        (&mut self.watch).await.unwrap();
+        self.store.clone()
+    }
+
+    // Walks the path from the mount point like the kernel does with lookups.
+    pub fn look_up(&self, path: &str) -> Option<u64> {
+        let mut inode = self.store.get_inode(MOUNT_POINT.to_string()).ok()?;
+        for name in path.split('/') {
+            inode = self.fs.inner.look_up(inode, OsStr::new(name)).ok()?.ino;
+        }
+        Some(inode)
+    }
+
+    pub fn read(&mut self, path: &str) -> Option<String> {
+        let inode = self.look_up(path)?;
+        let fh = self.fs.inner.open(inode, true, false, false).ok()?;
+        let data = self.fs.inner.read(inode, fh, 0, u32::MAX).ok();
+        self.fs.inner.release(fh);
+        Some(String::from_utf8(data?).unwrap())
+    }
+
+    // The stored content of the file, without going through a file handle.
+    pub fn content(&self, path: &str) -> Option<String> {
+        let data = self.store.get_data(self.look_up(path)?).ok()?;
+        Some(String::from_utf8(data.to_vec()).unwrap())
+    }
+
+    pub fn list(&self, path: &str) -> Vec<String> {
+        match self.look_up(path) {
+            Some(inode) => self.store.entries(inode).map(|e| e.unwrap().0).collect(),
+            None => vec![],
+        }
+    }
+
+    // Writes the content through a new file handle and flushes it, like an
+    // editor saving the file.
+    pub fn save(&mut self, path: &str, content: &str) -> Result<(), i32> {
+        let inode = self.look_up(path).ok_or(ENOENT)?;
+        self.write(inode, content)
+    }
+
+    // Creates the file in the directory and saves the content into it.
+    pub fn create(&mut self, dir: &str, name: &str, content: &str) -> Result<(), i32> {
+        let parent = self.look_up(dir).ok_or(ENOENT)?;
+        let attr = self
+            .fs
+            .inner
+            .create(parent, OsStr::new(name))
+            .map_err(|e| e.errno())?;
+        self.write(attr.ino, content)
+    }
+
+    pub fn remove(&mut self, dir: &str, name: &str) -> Result<(), i32> {
+        let parent = self.look_up(dir).ok_or(ENOENT)?;
+        tokio::task::block_in_place(|| self.fs.delete_from_cluster(parent, OsStr::new(name)))
+            .map_err(|e| e.errno())
+    }
+
+    fn write(&mut self, inode: u64, content: &str) -> Result<(), i32> {
+        let inner = &mut self.fs.inner;
+        let fh = inner
+            .open(inode, false, true, true)
+            .map_err(|e| e.errno())?;
+        let written = inner.write(fh, 0, content.as_bytes());
+        let flushed = written
+            .and_then(|_| tokio::task::block_in_place(|| self.fs.sync_to_cluster(inode, fh)));
+        self.fs.inner.release(fh);
+        flushed.map_err(|e| e.errno())
+    }
+
+    // Waits until the tree catches up with the server.
+    pub async fn wait_for(&self, what: &str, check: impl Fn(&Self) -> bool) {
+        for _ in 0..500 {
+            if check(self) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+}
@@ -0,0 +1,66 @@
+use kufu::config::{self, ClusterConfig, KubeConfig};
+
+fn clusters(server_a: &str, server_b: &str, token_a: &str) -> Vec<ClusterConfig> {
+    let raw = serde_yaml::from_str(&format!(
+        r#"
+apiVersion: v1
+kind: Config
+clusters:
+- name: a
+  cluster:
+    server: {}
+- name: b
+  cluster:
+    server: {}
+users:
+- name: a
+  user:
+    token: {}
+- name: b
+  user: {{}}
+contexts:
+- name: a
+  context:
+    cluster: a
+    user: a
+- name: b
+  context:
+    cluster: b
+    user: b
+current-context: a
+"#,
+        server_a, server_b, token_a
+    ))
+    .unwrap();
+    let kube_config = KubeConfig {
+        name: None,
+        config_path: None,
+        raw: Some(raw),
+        contexts: Some(vec!["*".to_string()]),
+        namespaces: None,
+    };
+    config::clusters(&[kube_config], "default").unwrap()
+}
+
+fn fingerprint(clusters: &[ClusterConfig], name: &str) -> String {
+    let cluster = clusters.iter().find(|c| c.name == name).unwrap();
+    cluster.fingerprint().unwrap()
+}
+
+#[test]
+fn fingerprint_follows_server_and_credentials_of_the_context() {
+    let before = clusters("https://a:6443", "https://b:6443", "one");
+    let moved = clusters("https://a2:6443", "https://b:6443", "one");
+    let rotated = clusters("https://a:6443", "https://b:6443", "two");
+    assert_ne!(fingerprint(&before, "a"), fingerprint(&moved, "a"));
+    assert_ne!(fingerprint(&before, "a"), fingerprint(&rotated, "a"));
+    assert_eq!(fingerprint(&before, "b"), fingerprint(&rotated, "b"));
+}
+
+#[test]
+fn fingerprint_ignores_other_contexts() {
+    let before = clusters("https://a:6443", "https://b:6443", "one");
+    let after = clusters("https://a:6443", "https://b2:6443", "one");
+    assert_eq!(fingerprint(&before, "a"), fingerprint(&after, "a"));
+    assert_ne!(fingerprint(&before, "b"), fingerprint(&after, "b"));
+}
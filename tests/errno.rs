@@ -0,0 +1,56 @@
+mod common;
+
+use common::*;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::ListParams, Api};
+use kufu::config::Layout;
+use kufu::error::Error;
+use libc::{EACCES, ECONNREFUSED, ENOENT};
+use std::ffi::OsStr;
+use tokio::net::TcpListener;
+
+async fn list_errno(api: Api<Pod>) -> i32 {
+    let e = api.list(&ListParams::default()).await.unwrap_err();
+    Error::from(e).errno()
+}
+
+#[tokio::test]
+async fn unreachable_cluster_is_connection_refused() {
+    // nothing listens on the port once the listener is gone
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let pods = Api::all(client(&kubeconfig(&addr.to_string())).await);
+    assert_eq!(list_errno(pods).await, ECONNREFUSED);
+}
+
+#[tokio::test]
+async fn api_errors_follow_the_status_code() {
+    let server = FakeApiServer::start(vec![FakeKind::namespace(), FakeKind::pod()]).await;
+    server.forbid_cluster_wide(&FakeKind::pod());
+    let pods = Api::all(client(&server.kubeconfig()).await);
+    assert_eq!(list_errno(pods).await, EACCES);
+
+    // pods are not served at all
+    let server = FakeApiServer::start(vec![FakeKind::namespace()]).await;
+    let pods = Api::all(client(&server.kubeconfig()).await);
+    assert_eq!(list_errno(pods).await, ENOENT);
+}
+
+#[tokio::test]
+async fn missing_files_are_not_found() {
+    let server = FakeApiServer::start(vec![FakeKind::namespace()]).await;
+    let mount = Mount::new(&server, &[FakeKind::namespace()], Layout::Kind).await;
+    let cluster = mount.look_up("default").unwrap();
+
+    let e = mount
+        .fs
+        .inner
+        .look_up(cluster, OsStr::new("missing.yaml"))
+        .unwrap_err();
+    assert_eq!(e.errno(), ENOENT);
+    let e = mount.fs.inner.read(u64::MAX >> 1, 0, 0, 16).unwrap_err();
+    assert_eq!(e.errno(), ENOENT);
+}
@@ -0,0 +1,323 @@
+mod common;
+
+use common::*;
+use kufu::config::Layout;
+use kufu::db::FSManger;
+use libc::{EACCES, EFBIG, EINVAL, EPERM};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+const POD_DIR: &str = "default/namespace/dev/pod";
+
+const WEB: &str = "default/namespace/dev/pod/web.yaml";
+
+async fn mount_web() -> (FakeApiServer, Mount) {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![pod("dev", "web")]).await;
+    let mount = Mount::with_file(&server, Layout::Kind, WEB).await;
+    (server, mount)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saved_file_replaces_the_object() {
+    let (server, mut mount) = mount_web().await;
+
+    let web = mount.read(WEB).unwrap();
+    mount
+        .save(WEB, &web.replace("image: nginx", "image: httpd"))
+        .unwrap();
+    let live = server.get(&pod("dev", "web")).unwrap();
+    assert_eq!(live["spec"]["containers"][0]["image"], "httpd");
+    // the accepted object is stored right away, not only with the watch event
+    let web = mount.content(WEB).unwrap();
+    assert!(web.contains("image: httpd"), "{}", web);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_are_read_back_before_the_flush() {
+    let (_server, mut mount) = mount_web().await;
+    let inode = mount.look_up(WEB).unwrap();
+
+    let inner = &mut mount.fs.inner;
+    let fh = inner.open(inode, true, true, true).unwrap();
+    inner.write(fh, 0, b"kind: Pod\n").unwrap();
+    assert_eq!(inner.read(inode, fh, 0, 64).unwrap(), b"kind: Pod\n");
+    assert_eq!(inner.get_attr(inode).unwrap().size, 10);
+    inner.release(fh);
+
+    // nothing was sent, so the file shows the object again
+    let web = mount.read(WEB).unwrap();
+    assert!(web.contains("image: nginx"), "{}", web);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn created_file_becomes_an_object() {
+    let (server, mut mount) = mount_web().await;
+
+    mount
+        .create(POD_DIR, "api.yaml", &yaml(&pod("dev", "api")))
+        .unwrap();
+    assert!(server.get(&pod("dev", "api")).is_some());
+    mount
+        .wait_for("created pod", |m| {
+            m.look_up("default/namespace/dev/pod/api.yaml").is_some()
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn created_file_shows_its_object_once_mounted() {
+    let (_server, mut mount) = mount_web().await;
+    let dir = mount.look_up(POD_DIR).unwrap();
+    let api = "default/namespace/dev/pod/api.yaml";
+
+    let inner = &mut mount.fs.inner;
+    let inode = inner.create(dir, OsStr::new("api.yaml")).unwrap().ino;
+    let fh = inner.open(inode, true, true, true).unwrap();
+    inner
+        .write(fh, 0, yaml(&pod("dev", "api")).as_bytes())
+        .unwrap();
+    tokio::task::block_in_place(|| mount.fs.sync_to_cluster(inode, fh)).unwrap();
+    mount
+        .wait_for("created pod", |m| m.content(api).is_some())
+        .await;
+
+    // the handle of the create is still open, the file is the object now
+    let content = mount.content(api).unwrap();
+    let inner = &mut mount.fs.inner;
+    assert_eq!(inner.get_attr(inode).unwrap().size, content.len() as u64);
+    let read = inner.open(inode, true, false, false).unwrap();
+    assert_eq!(
+        inner.read(inode, read, 0, 4096).unwrap(),
+        content.as_bytes()
+    );
+    inner.release(read);
+    inner.release(fh);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn created_file_must_match_its_object() {
+    let (server, mut mount) = mount_web().await;
+
+    let e = mount
+        .create(POD_DIR, "api.yaml", &yaml(&pod("dev", "db")))
+        .unwrap_err();
+    assert_eq!(e, EINVAL);
+    assert!(server.get(&pod("dev", "db")).is_none());
+    assert!(mount
+        .look_up("default/namespace/dev/pod/api.yaml")
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unlink_deletes_the_object() {
+    let (server, mut mount) = mount_web().await;
+
+    mount.remove(POD_DIR, "web.yaml").unwrap();
+    assert!(server.get(&pod("dev", "web")).is_none());
+    mount
+        .wait_for("deleted pod", |m| m.look_up(WEB).is_none())
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unlinked_pending_file_is_forgotten() {
+    let (server, mut mount) = mount_web().await;
+    let dir = mount.look_up(POD_DIR).unwrap();
+
+    mount.fs.inner.create(dir, OsStr::new("api.yaml")).unwrap();
+    assert!(mount
+        .look_up("default/namespace/dev/pod/api.yaml")
+        .is_some());
+    mount.remove(POD_DIR, "api.yaml").unwrap();
+    assert!(mount
+        .look_up("default/namespace/dev/pod/api.yaml")
+        .is_none());
+    assert!(server.get(&pod("dev", "api")).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edit_can_not_move_the_object() {
+    let (server, mut mount) = mount_web().await;
+    server.apply(pod("dev", "api"));
+    let api = server.get(&pod("dev", "api"));
+
+    let web = mount.read(WEB).unwrap();
+    let e = mount
+        .save(WEB, &web.replace("name: web", "name: api"))
+        .unwrap_err();
+    assert_eq!(e, EINVAL);
+    assert_eq!(server.get(&pod("dev", "api")), api);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn truncate_needs_a_write_handle() {
+    let (_server, mut mount) = mount_web().await;
+    let inode = mount.look_up(WEB).unwrap();
+
+    let e = mount.fs.inner.truncate(inode, None, 0).unwrap_err();
+    assert_eq!(e.errno(), EPERM);
+    let inner = &mut mount.fs.inner;
+    let fh = inner.open(inode, false, true, false).unwrap();
+    inner.truncate(inode, Some(fh), 0).unwrap();
+    assert_eq!(inner.get_attr(inode).unwrap().size, 0);
+    inner.release(fh);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_buffers_are_bounded() {
+    let (_server, mut mount) = mount_web().await;
+    let inode = mount.look_up(WEB).unwrap();
+
+    let inner = &mut mount.fs.inner;
+    let fh = inner.open(inode, true, true, false).unwrap();
+    let size = inner.get_attr(inode).unwrap().size;
+    let e = inner.write(fh, 1 << 40, b"x").unwrap_err();
+    assert_eq!(e.errno(), EFBIG);
+    let e = inner.write(fh, -1, b"x").unwrap_err();
+    assert_eq!(e.errno(), EINVAL);
+    let e = inner.truncate(inode, Some(fh), u64::MAX).unwrap_err();
+    assert_eq!(e.errno(), EFBIG);
+    assert_eq!(inner.get_attr(inode).unwrap().size, size);
+    inner.release(fh);
+}
+
+#[tokio::test]
+async fn listings_continue_after_the_last_name() {
+    let pods = vec![pod("dev", "api"), pod("dev", "db"), pod("dev", "web")];
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], pods).await;
+    let memory = Mount::with_file(&server, Layout::Kind, WEB).await;
+    let dir = SledDir::new("listing");
+    let sled = Mount::open(&server, &server.kinds(), Layout::Kind, dir.open().await).await;
+    sled.wait_for(WEB, |m| m.look_up(WEB).is_some()).await;
+
+    let memory_dir = memory.look_up(POD_DIR).unwrap();
+    let sled_dir = sled.look_up(POD_DIR).unwrap();
+    assert_eq!(
+        after(&memory.store, memory_dir, "api.yaml"),
+        ["db.yaml", "web.yaml"]
+    );
+    assert_eq!(
+        after(&sled.store, sled_dir, "api.yaml"),
+        ["db.yaml", "web.yaml"]
+    );
+    // the scan stops at the entries of other directories
+    assert!(after(&memory.store, memory_dir, "web.yaml").is_empty());
+    assert!(after(&sled.store, sled_dir, "web.yaml").is_empty());
+}
+
+fn after(store: &impl FSManger, dir: u64, name: &str) -> Vec<String> {
+    store
+        .entries_after(dir, name)
+        .map(|e| e.unwrap().0)
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn namespace_directories_are_kept_by_default() {
+    let (server, mut mount) = mount_web().await;
+    server.apply(namespace("qa"));
+    mount
+        .wait_for("empty namespace", |m| {
+            m.look_up("default/namespace/qa").is_some()
+        })
+        .await;
+
+    let root = mount.look_up("default/namespace").unwrap();
+    let e = tokio::task::block_in_place(|| mount.fs.delete_namespace(root, OsStr::new("qa")))
+        .unwrap_err();
+    assert_eq!(e.errno(), EPERM);
+    assert!(server.get(&namespace("qa")).is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn namespace_files_are_kept_by_default() {
+    let (server, mut mount) = mount_web().await;
+    server.apply(namespace("qa"));
+    mount
+        .wait_for("empty namespace", |m| {
+            m.look_up("default/namespace/qa.yaml").is_some()
+        })
+        .await;
+
+    let e = mount.remove("default/namespace", "qa.yaml").unwrap_err();
+    assert_eq!(e, EPERM);
+    assert!(server.get(&namespace("qa")).is_some());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mkdir_creates_a_namespace() {
+    let (server, mut mount) = mount_web().await;
+    let root = mount.look_up("default/namespace").unwrap();
+
+    tokio::task::block_in_place(|| mount.fs.create_namespace(root, OsStr::new("qa"))).unwrap();
+    assert!(server.get(&namespace("qa")).is_some());
+    mount
+        .wait_for("created namespace", |m| {
+            m.look_up("default/namespace/qa.yaml").is_some()
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rmdir_deletes_the_namespace_once_enabled() {
+    let (server, mut mount) = mount_web().await;
+    mount.allow_namespace_deletion();
+    server.apply(namespace("qa"));
+    mount
+        .wait_for("empty namespace", |m| {
+            m.look_up("default/namespace/qa").is_some()
+        })
+        .await;
+
+    let root = mount.look_up("default/namespace").unwrap();
+    let e = tokio::task::block_in_place(|| mount.fs.delete_namespace(root, OsStr::new("dev")))
+        .unwrap_err();
+    assert_eq!(e.errno(), libc::ENOTEMPTY);
+    tokio::task::block_in_place(|| mount.fs.delete_namespace(root, OsStr::new("qa"))).unwrap();
+    assert!(server.get(&namespace("qa")).is_none());
+    assert!(server.get(&namespace("dev")).is_some());
+    mount
+        .wait_for("deleted namespace", |m| {
+            m.look_up("default/namespace/qa.yaml").is_none()
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn namespace_names_must_be_utf8() {
+    let (_server, mut mount) = mount_web().await;
+    let root = mount.look_up("default/namespace").unwrap();
+    let name = OsStr::from_bytes(b"qa\xff");
+
+    let e = tokio::task::block_in_place(|| mount.fs.create_namespace(root, name)).unwrap_err();
+    assert_eq!(e.errno(), EINVAL);
+    let e = tokio::task::block_in_place(|| mount.fs.delete_namespace(root, name)).unwrap_err();
+    assert_eq!(e.errno(), EINVAL);
+}
+
+#[tokio::test]
+async fn secret_files_are_only_accessible_to_the_mounting_user() {
+    let server = FakeApiServer::with_dev(&[FakeKind::secret()], vec![db_secret()]).await;
+    let password = "default/namespace/dev/secret/db/password";
+    let mount = Mount::with_file(&server, Layout::Kind, password).await;
+    let user = unsafe { libc::getuid() };
+    let other = user + 1;
+
+    let key = mount.look_up(password).unwrap();
+    let inner = &mount.fs.inner;
+    inner.check_access(key, user, true, false).unwrap();
+    let e = inner.check_access(key, user, false, true).unwrap_err();
+    assert_eq!(e.errno(), EACCES);
+    let e = inner.check_access(key, other, true, false).unwrap_err();
+    assert_eq!(e.errno(), EACCES);
+
+    let yaml = mount
+        .look_up("default/namespace/dev/secret/db.yaml")
+        .unwrap();
+    inner.check_access(yaml, user, true, true).unwrap();
+    let e = inner.check_access(yaml, other, true, false).unwrap_err();
+    assert_eq!(e.errno(), EACCES);
+    // files of other objects are not checked
+    let namespace = mount.look_up("default/namespace/dev.yaml").unwrap();
+    inner.check_access(namespace, other, true, true).unwrap();
+}
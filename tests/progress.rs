@@ -0,0 +1,40 @@
+use kufu::kube::progress::Progress;
+
+fn rv(v: &str) -> Option<String> {
+    Some(v.to_string())
+}
+
+#[test]
+fn version_waits_for_earlier_events() {
+    let mut progress = Progress::new(rv("1"));
+    let web = progress.queued("dev/web", rv("2"));
+    let api = progress.queued("dev/api", rv("3"));
+
+    // web still waits for its retry, a restart has to replay it
+    assert_eq!(progress.processed("dev/api", api), None);
+    assert_eq!(progress.processed("dev/web", web), rv("3"));
+}
+
+#[test]
+fn newer_event_of_a_key_settles_the_older_ones() {
+    let mut progress = Progress::new(None);
+    let failed = progress.queued("dev/web", rv("2"));
+    let api = progress.queued("dev/api", rv("3"));
+    let web = progress.queued("dev/web", rv("4"));
+    assert!(failed < web);
+
+    assert_eq!(progress.processed("dev/api", api), None);
+    assert_eq!(progress.processed("dev/web", web), rv("4"));
+    // nothing left to save again
+    assert_eq!(progress.processed("dev/web", failed), None);
+}
+
+#[test]
+fn relist_moves_the_version_with_its_last_event() {
+    let mut progress = Progress::new(rv("1"));
+    let web = progress.queued("dev/web", None);
+    let api = progress.queued("dev/api", rv("5"));
+
+    assert_eq!(progress.processed("dev/api", api), None);
+    assert_eq!(progress.processed("dev/web", web), rv("5"));
+}
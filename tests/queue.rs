@@ -0,0 +1,30 @@
+use kufu::controller::queue::WorkQueue;
+use std::time::Duration;
+
+#[tokio::test]
+async fn failed_value_is_retried() {
+    let queue = WorkQueue::new();
+    queue.add("dev/web", "applied");
+    let (key, value) = queue.get().await.unwrap();
+    queue.add_rate_limited(key, value);
+    queue.done(&key);
+
+    assert_eq!(queue.get().await, Some(("dev/web", "applied")));
+}
+
+#[tokio::test]
+async fn retry_of_a_replaced_value_is_dropped() {
+    let queue = WorkQueue::new();
+    queue.add("dev/web", "applied");
+    let (key, value) = queue.get().await.unwrap();
+    // the object is deleted while its apply fails
+    queue.add("dev/web", "deleted");
+    queue.add_rate_limited(key, value);
+    queue.done(&key);
+
+    assert_eq!(queue.get().await, Some(("dev/web", "deleted")));
+    queue.forget(&key);
+    queue.done(&key);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(queue.is_empty());
+}
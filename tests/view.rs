@@ -0,0 +1,263 @@
+mod common;
+
+use kube::core::{DynamicObject, GroupVersionKind};
+use kube::discovery::{ApiCapabilities, ApiResource, Scope};
+use kufu::config::{Format, View};
+use kufu::view::{dir_view, merge, split, unredact, REDACTED};
+use kufu::{ClusterObject, ClusterObjectMeta};
+
+fn web() -> DynamicObject {
+    serde_yaml::from_str(
+        "apiVersion: v1
+kind: Pod
+metadata:
+  name: web
+  namespace: dev
+  labels:
+    app: web
+spec:
+  containers:
+  - name: app
+    image: nginx
+status:
+  phase: Running
+",
+    )
+    .unwrap()
+}
+
+fn part(obj: &DynamicObject, name: &str) -> String {
+    part_in(obj, name, Format::Yaml)
+}
+
+fn part_in(obj: &DynamicObject, name: &str, format: Format) -> String {
+    let (_, content) = split(obj, format)
+        .unwrap()
+        .into_iter()
+        .find(|(part, _)| part == name)
+        .unwrap();
+    String::from_utf8(content.to_vec()).unwrap()
+}
+
+#[test]
+fn edited_parts_are_merged_into_the_object() {
+    let mut obj = web();
+    let spec = part(&obj, "spec.yaml").replace("nginx", "nginx:1.25");
+    merge(&mut obj, "spec.yaml", spec.as_bytes(), Format::Yaml).unwrap();
+    merge(
+        &mut obj,
+        "metadata/labels",
+        b"app: web\ntier: front\n",
+        Format::Yaml,
+    )
+    .unwrap();
+
+    assert_eq!(obj.data["spec"]["containers"][0]["image"], "nginx:1.25");
+    assert_eq!(obj.data["status"]["phase"], "Running");
+    assert_eq!(obj.metadata.labels.as_ref().unwrap().len(), 2);
+    assert_eq!(obj.metadata.name.as_deref(), Some("web"));
+    assert_eq!(part(&obj, "metadata/labels"), "app: web\ntier: front\n");
+}
+
+#[test]
+fn emptied_parts_drop_their_field() {
+    let mut obj = web();
+    merge(&mut obj, "metadata/labels", b"\n", Format::Yaml).unwrap();
+    merge(&mut obj, "status.yaml", b"", Format::Yaml).unwrap();
+
+    assert!(obj.metadata.labels.is_none());
+    assert!(obj.data.get("status").is_none());
+    assert!(obj.data.get("spec").is_some());
+}
+
+#[test]
+fn unknown_parts_are_rejected() {
+    let mut obj = web();
+    assert!(merge(&mut obj, "metadata/finalizers", b"[]", Format::Yaml).is_err());
+    assert!(merge(&mut obj, "spec.json", b"{}", Format::Yaml).is_err());
+}
+
+#[test]
+fn objects_round_trip_through_every_format() {
+    let obj = web();
+    for format in [Format::Yaml, Format::Json, Format::JsonLines] {
+        let data = format.render(&obj).unwrap();
+        let parsed = format.parse(&data).unwrap();
+        assert_eq!(parsed.metadata.name.as_deref(), Some("web"), "{:?}", format);
+        assert_eq!(parsed.data, obj.data, "{:?}", format);
+    }
+    let line = Format::JsonLines.render(&obj).unwrap();
+    assert_eq!(line.iter().filter(|b| **b == b'\n').count(), 1);
+    assert!(line.starts_with(b"{\"apiVersion\":\"v1\""));
+}
+
+#[test]
+fn split_parts_follow_the_format() {
+    let mut obj = web();
+    assert_eq!(
+        part_in(&obj, "metadata/labels", Format::Json),
+        "{\n  \"app\": \"web\"\n}\n"
+    );
+    let spec = part_in(&obj, "spec.json", Format::Json).replace("nginx", "nginx:1.25");
+    merge(&mut obj, "spec.json", spec.as_bytes(), Format::Json).unwrap();
+    assert_eq!(obj.data["spec"]["containers"][0]["image"], "nginx:1.25");
+    assert!(merge(&mut obj, "spec.yaml", b"{}", Format::Json).is_err());
+}
+
+fn served_web() -> DynamicObject {
+    let mut obj = web();
+    obj.metadata.uid = Some("8d3c".to_string());
+    obj.metadata.resource_version = Some("42".to_string());
+    obj.metadata.generation = Some(3);
+    obj.metadata.annotations = Some(
+        [
+            (
+                "kubectl.kubernetes.io/last-applied-configuration".to_string(),
+                "{}".to_string(),
+            ),
+            ("team".to_string(), "web".to_string()),
+        ]
+        .into(),
+    );
+    obj
+}
+
+#[test]
+fn neat_strips_what_the_server_manages() {
+    let neat = kufu::view::neat(&served_web());
+    let yaml = String::from_utf8(Format::Yaml.render(&neat).unwrap().to_vec()).unwrap();
+    for noise in [
+        "uid",
+        "resourceVersion",
+        "generation",
+        "last-applied",
+        "status",
+    ] {
+        assert!(!yaml.contains(noise), "{} in {}", noise, yaml);
+    }
+    assert!(yaml.contains("team: web"), "{}", yaml);
+    assert!(yaml.contains("image: nginx"), "{}", yaml);
+}
+
+#[test]
+fn neat_edits_are_merged_against_the_full_object() {
+    let mut obj = served_web();
+    let neat = Format::Yaml.render(&kufu::view::neat(&obj)).unwrap();
+    let edited = String::from_utf8(neat.to_vec())
+        .unwrap()
+        .replace("image: nginx", "image: nginx:1.25");
+    merge(&mut obj, "neat", edited.as_bytes(), Format::Yaml).unwrap();
+
+    assert_eq!(obj.data["spec"]["containers"][0]["image"], "nginx:1.25");
+    assert_eq!(obj.data["status"]["phase"], "Running");
+    assert_eq!(obj.metadata.resource_version.as_deref(), Some("42"));
+    assert_eq!(obj.metadata.uid.as_deref(), Some("8d3c"));
+    let annotations = obj.metadata.annotations.unwrap();
+    assert!(annotations.contains_key("kubectl.kubernetes.io/last-applied-configuration"));
+    assert_eq!(annotations["team"], "web");
+}
+
+#[test]
+fn neat_edits_keep_the_name() {
+    let mut obj = served_web();
+    let neat = Format::Yaml.render(&kufu::view::neat(&obj)).unwrap();
+    let renamed = String::from_utf8(neat.to_vec())
+        .unwrap()
+        .replace("name: web", "name: api");
+    assert!(merge(&mut obj, "neat", renamed.as_bytes(), Format::Yaml).is_err());
+}
+
+#[test]
+fn neat_edits_keep_the_kind() {
+    let mut obj = served_web();
+    let neat = String::from_utf8(
+        Format::Yaml
+            .render(&kufu::view::neat(&obj))
+            .unwrap()
+            .to_vec(),
+    )
+    .unwrap();
+    for (from, to) in [
+        ("kind: Pod", "kind: Service"),
+        ("apiVersion: v1", "apiVersion: v2"),
+    ] {
+        let edited = neat.replace(from, to);
+        assert_ne!(edited, neat);
+        assert!(merge(&mut obj, "neat", edited.as_bytes(), Format::Yaml).is_err());
+    }
+}
+
+fn secret_meta(view: View) -> ClusterObjectMeta {
+    let ar = ApiResource::from_gvk(&GroupVersionKind::gvk("", "v1", "Secret"));
+    let caps = ApiCapabilities {
+        scope: Scope::Namespaced,
+        subresources: vec![],
+        operations: vec![],
+    };
+    ClusterObjectMeta::new("default".to_string(), &ar, caps, view)
+}
+
+fn db_secret() -> DynamicObject {
+    serde_json::from_value(common::db_secret()).unwrap()
+}
+
+#[test]
+fn secrets_are_redacted_unless_revealed() {
+    let obj = db_secret();
+    let meta = secret_meta(View::default());
+    let cluster_obj = ClusterObject {
+        meta: &meta,
+        obj: &obj,
+    };
+    let shown = kufu::view::shown(&cluster_obj);
+    assert_eq!(shown.data["data"]["password"], REDACTED);
+    assert!(shown.metadata.annotations.as_ref().unwrap().is_empty());
+    let keys = dir_view(&cluster_obj).unwrap();
+    assert_eq!(keys.len(), 2);
+    assert!(keys
+        .iter()
+        .all(|k| k.mode == Some(0o400) && &k.content[..] == b"<redacted>\n"));
+
+    let meta = secret_meta(View {
+        reveal_secrets: true,
+        ..Default::default()
+    });
+    let cluster_obj = ClusterObject {
+        meta: &meta,
+        obj: &obj,
+    };
+    assert_eq!(
+        kufu::view::shown(&cluster_obj).data["data"]["password"],
+        "aHVudGVyMg=="
+    );
+    let keys = dir_view(&cluster_obj).unwrap();
+    assert_eq!(keys[0].path, "password");
+    assert_eq!(keys[0].part.as_deref(), Some("data/password"));
+    assert_eq!(&keys[0].content[..], b"hunter2");
+}
+
+#[test]
+fn edited_secret_keys_are_encoded_and_redacted_values_restored() {
+    let live = db_secret();
+    let meta = secret_meta(View::default());
+    let mut obj = kufu::view::shown(&ClusterObject {
+        meta: &meta,
+        obj: &live,
+    })
+    .into_owned();
+    merge(&mut obj, "data/password", b"s3cret", Format::Yaml).unwrap();
+    merge(&mut obj, "data/user", b"<redacted>\n", Format::Yaml).unwrap();
+    assert_eq!(obj.data["data"]["password"], "czNjcmV0");
+    assert_eq!(obj.data["data"]["user"], REDACTED);
+
+    obj.data["data"]["token"] = serde_json::json!(REDACTED);
+    unredact(&mut obj, &live);
+    assert_eq!(obj.data["data"]["password"], "czNjcmV0");
+    assert_eq!(obj.data["data"]["user"], "YWRtaW4=");
+    assert!(obj.data["data"].get("token").is_none());
+    assert!(obj
+        .metadata
+        .annotations
+        .unwrap()
+        .contains_key("kubectl.kubernetes.io/last-applied-configuration"));
+}
@@ -0,0 +1,606 @@
+mod common;
+
+use common::*;
+use kufu::config::{Discovery, Format, Layout, View};
+use kufu::db::{FSManger, MemoryDb};
+use kufu::kube::WatchOptions;
+use std::time::Duration;
+
+const WEB: &str = "default/namespace/dev/pod/web.yaml";
+
+#[tokio::test]
+async fn mounts_listed_objects_and_follows_events() {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![pod("dev", "web")]).await;
+    let mut mount = Mount::with_file(&server, Layout::Kind, WEB).await;
+    let web = mount.read(WEB).unwrap();
+    assert!(web.contains("name: web"), "{}", web);
+    assert!(web.contains("image: nginx"), "{}", web);
+    assert_eq!(mount.list("default/namespace"), ["dev", "dev.yaml"]);
+
+    server.apply(pod("dev", "api"));
+    mount
+        .wait_for("added pod", |m| {
+            m.look_up("default/namespace/dev/pod/api.yaml").is_some()
+        })
+        .await;
+
+    server.delete(pod("dev", "web"));
+    mount
+        .wait_for("deleted pod", |m| {
+            m.look_up("default/namespace/dev/pod/web.yaml").is_none()
+        })
+        .await;
+    assert_eq!(mount.list("default/namespace/dev/pod"), ["api.yaml"]);
+}
+
+#[tokio::test]
+async fn layout_keeps_same_named_kinds_apart() {
+    let core_event = FakeKind::new("v1", "Event", "events", true);
+    let event = FakeKind::new("events.k8s.io/v1", "Event", "events", true);
+    let kinds = vec![FakeKind::namespace(), core_event.clone(), event.clone()];
+    let server = FakeApiServer::start(kinds.clone()).await;
+    server.apply(namespace("dev"));
+    server.apply(object(&core_event, Some("dev"), "started"));
+    server.apply(object(&event, Some("dev"), "pulled"));
+
+    let mount = Mount::new(&server, &kinds, Layout::ResourceGroup).await;
+    mount
+        .wait_for("both event kinds", |m| {
+            m.look_up("default/namespace/dev/events/started.yaml")
+                .is_some()
+                && m.look_up("default/namespace/dev/events.events.k8s.io/pulled.yaml")
+                    .is_some()
+        })
+        .await;
+    assert_eq!(
+        mount.list("default/namespace/dev"),
+        ["events", "events.events.k8s.io"]
+    );
+}
+
+#[tokio::test]
+async fn kind_layout_skips_same_named_kinds_of_other_groups() {
+    let core_event = FakeKind::new("v1", "Event", "events", true);
+    let event = FakeKind::new("events.k8s.io/v1", "Event", "events", true);
+    let server = FakeApiServer::with_dev(
+        &[core_event.clone(), event.clone()],
+        vec![
+            object(&core_event, Some("dev"), "started"),
+            object(&event, Some("dev"), "pulled"),
+        ],
+    )
+    .await;
+    let opts = WatchOptions {
+        discovery: Some(Discovery::default()),
+        ..Default::default()
+    };
+    let mount = Mount::with_options(&server, opts, MemoryDb::new()).await;
+    mount
+        .wait_for("core event", |m| {
+            m.look_up("default/namespace/dev/event/started.yaml")
+                .is_some()
+        })
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mount.list("default/namespace/dev/event"), ["started.yaml"]);
+}
+
+#[tokio::test]
+async fn namespace_root_outlives_the_last_namespace() {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![]).await;
+    let mount = Mount::with_file(&server, Layout::Kind, "default/namespace/dev.yaml").await;
+
+    server.delete(namespace("dev"));
+    mount
+        .wait_for("deleted namespace", |m| {
+            m.look_up("default/namespace/dev.yaml").is_none()
+        })
+        .await;
+    assert!(mount.look_up("default/namespace").is_some());
+    assert!(mount.list("default/namespace").is_empty());
+
+    server.apply(namespace("qa"));
+    server.apply(pod("qa", "web"));
+    mount
+        .wait_for("pod of a new namespace", |m| {
+            m.look_up("default/namespace/qa/pod/web.yaml").is_some()
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn relists_when_the_version_is_gone() {
+    let server = FakeApiServer::with_dev(
+        &[FakeKind::pod()],
+        vec![pod("dev", "web"), pod("dev", "api")],
+    )
+    .await;
+
+    let mount = Mount::new(&server, &[FakeKind::pod()], Layout::Kind).await;
+    mount
+        .wait_for("listed pods", |m| {
+            m.list("default/namespace/dev/pod").len() == 2
+        })
+        .await;
+
+    // the deletion never reaches the watch, only the relist after the 410 finds it
+    server.delete_quietly(pod("dev", "web"));
+    server.compact();
+    mount
+        .wait_for("pruned pod", |m| {
+            m.list("default/namespace/dev/pod") == ["api.yaml"]
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn resync_prunes_missed_deletes_and_keeps_placeholders() {
+    let server = FakeApiServer::with_dev(
+        &[FakeKind::pod()],
+        vec![pod("dev", "web"), pod("dev", "api")],
+    )
+    .await;
+    // dev is no watched namespace, its directory is a placeholder for the pods
+    let mut namespaces = FakeKind::namespace().resource();
+    namespaces.label_selector = Some("team=ops".to_string());
+    let opts = WatchOptions {
+        resources: vec![namespaces, FakeKind::pod().resource()],
+        resync_period: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mount = Mount::with_options(&server, opts, MemoryDb::new()).await;
+    mount
+        .wait_for("listed pods", |m| {
+            m.list("default/namespace/dev/pod").len() == 2
+        })
+        .await;
+
+    server.delete_quietly(pod("dev", "web"));
+    mount
+        .wait_for("pruned pod", |m| {
+            m.list("default/namespace/dev/pod") == ["api.yaml"]
+        })
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mount.list("default/namespace"), ["dev", "dev.yaml"]);
+}
+
+#[tokio::test]
+async fn watches_namespaces_one_by_one_when_cluster_wide_is_forbidden() {
+    let server = FakeApiServer::start(vec![FakeKind::namespace(), FakeKind::pod()]).await;
+    server.apply(namespace("dev"));
+    server.apply(namespace("prod"));
+    server.apply(pod("dev", "web"));
+    server.apply(pod("prod", "api"));
+    server.forbid_cluster_wide(&FakeKind::pod());
+
+    let mount = Mount::new(&server, &[FakeKind::pod()], Layout::Kind).await;
+    mount
+        .wait_for("pods of both namespaces", |m| {
+            m.look_up("default/namespace/dev/pod/web.yaml").is_some()
+                && m.look_up("default/namespace/prod/pod/api.yaml").is_some()
+        })
+        .await;
+    assert!(server
+        .requests()
+        .iter()
+        .any(|r| r.starts_with("/api/v1/namespaces/prod/pods?")));
+}
+
+#[tokio::test]
+async fn namespaces_watched_one_by_one_follow_the_cluster() {
+    let server = FakeApiServer::start(vec![FakeKind::namespace(), FakeKind::pod()]).await;
+    server.apply(namespace("dev"));
+    server.apply(namespace("prod"));
+    server.apply(pod("dev", "web"));
+    server.apply(pod("prod", "api"));
+    server.forbid_cluster_wide(&FakeKind::pod());
+    let opts = WatchOptions {
+        resources: vec![FakeKind::pod().resource()],
+        resync_period: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let mount = Mount::with_options(&server, opts, MemoryDb::new()).await;
+    mount
+        .wait_for("pods of both namespaces", |m| {
+            m.look_up("default/namespace/dev/pod/web.yaml").is_some()
+                && m.look_up("default/namespace/prod/pod/api.yaml").is_some()
+        })
+        .await;
+
+    server.apply(namespace("qa"));
+    server.apply(pod("qa", "db"));
+    server.delete(namespace("prod"));
+    mount
+        .wait_for("pods of the current namespaces", |m| {
+            m.look_up("default/namespace/qa/pod/db.yaml").is_some()
+                && m.look_up("default/namespace/prod/pod/api.yaml").is_none()
+        })
+        .await;
+    assert!(mount
+        .look_up("default/namespace/dev/pod/web.yaml")
+        .is_some());
+}
+
+#[tokio::test]
+async fn resumes_from_the_stored_version_after_restart() {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![pod("dev", "web")]).await;
+    let mount = Mount::with_file(&server, Layout::Kind, WEB).await;
+    let store = mount.unmount().await;
+
+    // missed while kufu was down, delivered by the resumed watch
+    server.close_watches();
+    server.apply(pod("dev", "api"));
+    let listed = server.requests().len();
+
+    let mount = Mount::open(&server, &server.kinds(), Layout::Kind, store).await;
+    assert!(mount.look_up(WEB).is_some());
+    mount
+        .wait_for("pod added while down", |m| {
+            m.look_up("default/namespace/dev/pod/api.yaml").is_some()
+        })
+        .await;
+    // the probe for cluster wide access is no list of the kind
+    let lists: Vec<String> = server.requests()[listed..]
+        .iter()
+        .filter(|r| r.contains("/pods") && !r.contains("watch=true") && !r.contains("limit=1"))
+        .cloned()
+        .collect();
+    assert!(lists.is_empty(), "pods were listed again: {:?}", lists);
+}
+
+#[tokio::test]
+async fn resumes_from_the_sled_tree_after_restart() {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![pod("dev", "web")]).await;
+    let dir = SledDir::new("resume");
+    let mount = Mount::open(&server, &server.kinds(), Layout::Kind, dir.open().await).await;
+    mount.wait_for(WEB, |m| m.look_up(WEB).is_some()).await;
+    drop(mount.unmount().await);
+
+    server.close_watches();
+    server.apply(pod("dev", "api"));
+    let listed = server.requests().len();
+
+    // the reopened tree keeps its objects and the versions to resume from
+    let store = dir.open().await;
+    assert!(store.get_inode(WEB.to_string()).is_ok());
+    let mount = Mount::open(&server, &server.kinds(), Layout::Kind, store).await;
+    mount
+        .wait_for("pod added while down", |m| {
+            m.look_up("default/namespace/dev/pod/api.yaml").is_some()
+        })
+        .await;
+    assert!(mount.look_up(WEB).is_some());
+    let lists: Vec<String> = server.requests()[listed..]
+        .iter()
+        .filter(|r| r.contains("/pods") && !r.contains("watch=true") && !r.contains("limit=1"))
+        .cloned()
+        .collect();
+    assert!(lists.is_empty(), "pods were listed again: {:?}", lists);
+}
+
+#[tokio::test]
+async fn removed_kinds_do_not_block_resuming() {
+    let widget = FakeKind::new("example.com/v1", "Widget", "widgets", true);
+    let server = FakeApiServer::with_dev(
+        &[FakeKind::pod(), widget.clone()],
+        vec![pod("dev", "web"), object(&widget, Some("dev"), "gear")],
+    )
+    .await;
+    let opts = || WatchOptions {
+        discovery: Some(Discovery {
+            period: Some(1),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let gear = "default/namespace/dev/widget/gear.yaml";
+    let mount = Mount::with_options(&server, opts(), MemoryDb::new()).await;
+    mount.wait_for(gear, |m| m.look_up(gear).is_some()).await;
+
+    server.withdraw(&widget);
+    mount
+        .wait_for("uninstalled widget", |m| m.look_up(gear).is_none())
+        .await;
+    let store = mount.unmount().await;
+
+    let listed = server.requests().len();
+    let mount = Mount::with_options(&server, opts(), store).await;
+    assert!(mount.look_up(WEB).is_some());
+    mount
+        .wait_for("pod watch", |m| {
+            m.look_up(WEB).is_some()
+                && server.requests()[listed..]
+                    .iter()
+                    .any(|r| r.contains("/pods") && r.contains("watch=true"))
+        })
+        .await;
+    let lists: Vec<String> = server.requests()[listed..]
+        .iter()
+        .filter(|r| r.contains("/pods") && !r.contains("watch=true") && !r.contains("limit=1"))
+        .cloned()
+        .collect();
+    assert!(lists.is_empty(), "pods were listed again: {:?}", lists);
+}
+
+#[tokio::test]
+async fn split_view_follows_the_object() {
+    let mut web = pod("dev", "web");
+    web["metadata"]["labels"] = serde_json::json!({"app": "web"});
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![web.clone()]).await;
+    let view = View {
+        split: true,
+        ..Default::default()
+    };
+    let mut mount =
+        Mount::with_file(&server, view, "default/namespace/dev/pod/web/spec.yaml").await;
+    assert_eq!(mount.list("default/namespace/dev/pod"), ["web", "web.yaml"]);
+    assert_eq!(
+        mount.list("default/namespace/dev/pod/web"),
+        ["metadata", "spec.yaml"]
+    );
+    assert_eq!(
+        mount
+            .read("default/namespace/dev/pod/web/metadata/labels")
+            .unwrap(),
+        "app: web\n"
+    );
+    assert_eq!(
+        mount
+            .read("default/namespace/dev/pod/web/metadata/annotations")
+            .unwrap(),
+        ""
+    );
+    let spec = mount
+        .read("default/namespace/dev/pod/web/spec.yaml")
+        .unwrap();
+    assert!(spec.starts_with("containers:"), "{}", spec);
+    // namespace directories hold the namespaced objects, so they are not split
+    assert_eq!(mount.list("default/namespace/dev"), ["pod"]);
+
+    web["status"] = serde_json::json!({"phase": "Running"});
+    server.apply(web.clone());
+    mount
+        .wait_for("status part", |m| {
+            m.look_up("default/namespace/dev/pod/web/status.yaml")
+                .is_some()
+        })
+        .await;
+    assert_eq!(
+        mount
+            .read("default/namespace/dev/pod/web/status.yaml")
+            .unwrap(),
+        "phase: Running\n"
+    );
+
+    web.as_object_mut().unwrap().remove("status");
+    server.apply(web);
+    mount
+        .wait_for("dropped status part", |m| {
+            m.look_up("default/namespace/dev/pod/web/status.yaml")
+                .is_none()
+        })
+        .await;
+
+    server.delete(pod("dev", "web"));
+    mount
+        .wait_for("deleted pod", |m| {
+            m.look_up("default/namespace/dev/pod").is_none()
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn mounts_every_format_next_to_each_other() {
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![pod("dev", "web")]).await;
+    let view = View {
+        formats: vec![Format::Json, Format::Yaml, Format::JsonLines],
+        ..Default::default()
+    };
+    let mut mount = Mount::with_file(&server, view, WEB).await;
+    assert_eq!(
+        mount.list("default/namespace/dev/pod"),
+        ["web.json", "web.jsonl", "web.yaml"]
+    );
+    assert_eq!(
+        mount.list("default/namespace"),
+        ["dev", "dev.json", "dev.jsonl", "dev.yaml"]
+    );
+    let json = mount.read("default/namespace/dev/pod/web.json").unwrap();
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["spec"]["containers"][0]["image"], "nginx");
+    let line = mount.read("default/namespace/dev/pod/web.jsonl").unwrap();
+    assert_eq!(line.lines().count(), 1);
+
+    let mut web = pod("dev", "web");
+    web["spec"]["containers"][0]["image"] = serde_json::json!("nginx:1.25");
+    server.apply(web);
+    mount
+        .wait_for("updated json lines", |m| {
+            m.content("default/namespace/dev/pod/web.jsonl")
+                .is_some_and(|line| line.contains("nginx:1.25"))
+        })
+        .await;
+
+    server.delete(pod("dev", "web"));
+    mount
+        .wait_for("deleted pod", |m| {
+            m.look_up("default/namespace/dev/pod").is_none()
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn neat_file_follows_the_object() {
+    let mut web = pod("dev", "web");
+    web["status"] = serde_json::json!({"phase": "Pending"});
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], vec![web.clone()]).await;
+    let view = View {
+        neat: true,
+        ..Default::default()
+    };
+    let mut mount =
+        Mount::with_file(&server, view, "default/namespace/dev/pod/web.neat.yaml").await;
+    assert_eq!(
+        mount.list("default/namespace/dev/pod"),
+        ["web.neat.yaml", "web.yaml"]
+    );
+    let neat = mount
+        .read("default/namespace/dev/pod/web.neat.yaml")
+        .unwrap();
+    assert!(!neat.contains("resourceVersion"), "{}", neat);
+    assert!(!neat.contains("Pending"), "{}", neat);
+    assert!(neat.contains("image: nginx"), "{}", neat);
+
+    web["spec"]["containers"][0]["image"] = serde_json::json!("nginx:1.25");
+    server.apply(web);
+    mount
+        .wait_for("updated neat file", |m| {
+            m.content("default/namespace/dev/pod/web.neat.yaml")
+                .is_some_and(|neat| neat.contains("nginx:1.25"))
+        })
+        .await;
+
+    server.delete(pod("dev", "web"));
+    mount
+        .wait_for("deleted pod", |m| {
+            m.look_up("default/namespace/dev/pod").is_none()
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn object_files_win_paths_of_dotted_names() {
+    let pods = ["app", "app.yaml", "web", "web.neat"].map(|name| pod("dev", name));
+    let server = FakeApiServer::with_dev(&[FakeKind::pod()], pods.to_vec()).await;
+    let view = View {
+        split: true,
+        neat: true,
+        ..Default::default()
+    };
+    let mut mount = Mount::with_file(
+        &server,
+        view,
+        "default/namespace/dev/pod/web.neat.neat.yaml",
+    )
+    .await;
+    // app.yaml keeps its file but not its view directory, web.neat takes the
+    // path of the neat file of web
+    assert_eq!(
+        mount.list("default/namespace/dev/pod"),
+        [
+            "app",
+            "app.neat.yaml",
+            "app.yaml",
+            "app.yaml.neat.yaml",
+            "app.yaml.yaml",
+            "web",
+            "web.neat",
+            "web.neat.neat.yaml",
+            "web.neat.yaml",
+            "web.yaml"
+        ]
+    );
+    let app = mount.read("default/namespace/dev/pod/app.yaml").unwrap();
+    assert!(app.contains("name: app\n"), "{}", app);
+    let web_neat = mount
+        .read("default/namespace/dev/pod/web.neat.yaml")
+        .unwrap();
+    assert!(web_neat.contains("name: web.neat"), "{}", web_neat);
+    assert!(web_neat.contains("resourceVersion"), "{}", web_neat);
+
+    server.delete(pod("dev", "app"));
+    server.delete(pod("dev", "web.neat"));
+    mount
+        .wait_for("deleted pods", |m| {
+            m.look_up("default/namespace/dev/pod/web.neat").is_none()
+                && m.look_up("default/namespace/dev/pod/app").is_none()
+        })
+        .await;
+    // the files of the remaining objects are left alone
+    assert!(mount
+        .look_up("default/namespace/dev/pod/app.yaml.neat.yaml")
+        .is_some());
+    assert!(mount.look_up("default/namespace/dev/pod/web").is_some());
+
+    let mut web = pod("dev", "web");
+    web["spec"]["containers"][0]["image"] = serde_json::json!("nginx:1.25");
+    server.apply(web);
+    server.apply(pod("dev", "app.yaml"));
+    mount
+        .wait_for("freed paths", |m| {
+            m.content("default/namespace/dev/pod/web.neat.yaml")
+                .is_some_and(|neat| neat.contains("nginx:1.25"))
+                && m.look_up("default/namespace/dev/pod/app.yaml/spec.yaml")
+                    .is_some()
+        })
+        .await;
+    let neat = mount
+        .read("default/namespace/dev/pod/web.neat.yaml")
+        .unwrap();
+    assert!(!neat.contains("resourceVersion"), "{}", neat);
+}
+
+const PASSWORD: &str = "default/namespace/dev/secret/db/password";
+
+#[tokio::test]
+async fn secret_keys_are_private_and_redacted_by_default() {
+    let server = FakeApiServer::with_dev(&[FakeKind::secret()], vec![db_secret()]).await;
+    let mut mount = Mount::with_file(&server, Layout::Kind, PASSWORD).await;
+    assert_eq!(
+        mount.list("default/namespace/dev/secret"),
+        ["db", "db.yaml"]
+    );
+    for (path, perm) in [
+        (PASSWORD, 0o400),
+        ("default/namespace/dev/secret/db.yaml", 0o600),
+    ] {
+        let attr = mount
+            .fs
+            .inner
+            .get_attr(mount.look_up(path).unwrap())
+            .unwrap();
+        assert_eq!(attr.perm, perm, "{}", path);
+        assert_eq!(attr.uid, unsafe { libc::getuid() });
+    }
+    assert_eq!(mount.read(PASSWORD).unwrap(), "<redacted>\n");
+    let yaml = mount.read("default/namespace/dev/secret/db.yaml").unwrap();
+    assert!(yaml.contains("password: <redacted>"), "{}", yaml);
+    assert!(!yaml.contains("aHVudGVyMg=="), "{}", yaml);
+}
+
+#[tokio::test]
+async fn revealed_secret_keys_hold_the_decoded_values() {
+    let server = FakeApiServer::with_dev(&[FakeKind::secret()], vec![db_secret()]).await;
+    let view = View {
+        formats: vec![Format::Yaml, Format::Json],
+        neat: true,
+        reveal_secrets: true,
+        ..Default::default()
+    };
+    let mut mount = Mount::with_file(&server, view, PASSWORD).await;
+    assert_eq!(mount.read(PASSWORD).unwrap(), "hunter2");
+    // the values are in every file of the secret
+    for file in ["db.yaml", "db.json", "db.neat.yaml"] {
+        let path = format!("default/namespace/dev/secret/{}", file);
+        let attr = mount
+            .fs
+            .inner
+            .get_attr(mount.look_up(&path).unwrap())
+            .unwrap();
+        assert_eq!(attr.perm, 0o600, "{}", path);
+    }
+
+    let mut rotated = db_secret();
+    rotated["data"] = serde_json::json!({"token": "czNjcmV0"});
+    server.apply(rotated);
+    mount
+        .wait_for("rotated keys", |m| {
+            m.list("default/namespace/dev/secret/db") == ["token"]
+        })
+        .await;
+    assert_eq!(
+        mount.read("default/namespace/dev/secret/db/token").unwrap(),
+        "s3cret"
+    );
+}